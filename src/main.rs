@@ -3,11 +3,21 @@ pub mod types;
 
 use clap::Parser;
 use network::client;
-use network::replica::Replica;
+use network::history;
+use network::kv::KvStore;
+use network::nemesis::{NemesisConfig, Rule};
+use network::replica::{Replica, ReplicaConfig};
+use network::simulator::{SimConfig, Simulation};
+use network::transport::{
+    MemoryTransport, TcpTransport, Transport, TransportKind, UnixTransport, WireFormat,
+};
+use network::wal::FsyncPolicy;
 use smol::io;
 use std::net::SocketAddr;
 use std::process::exit;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 ///
 #[derive(Parser)]
@@ -49,6 +59,18 @@ struct Cli {
     #[arg(short, long, default_value_t = 0.02)]
     rate: f64,
 
+    /// Fraction [0,1] of the generated requests that are increments instead of writes
+    #[arg(long, default_value_t = 0.0)]
+    increments: f64,
+
+    /// Fraction [0,1] of the generated requests that are transactions transferring one unit between two keys
+    #[arg(long, default_value_t = 0.0)]
+    transfers: f64,
+
+    /// Fraction [0,1] of the generated requests that are reads instead of writes
+    #[arg(long, default_value_t = 0.0)]
+    reads: f64,
+
     /// Save - instruct replica to save its state on local disk, all other flags are ignored
     #[arg(short, long)]
     save: Option<String>,
@@ -56,15 +78,261 @@ struct Cli {
     /// Generate requests as a client for this many seconds
     #[arg(short, long, default_value_t = 5)] // find the val [FIXME]
     experiment_time: u64,
+
+    /// Milliseconds a generating client waits for a reply before sending the request to the next replica given with --connections
+    #[arg(long, default_value_t = 1000)]
+    request_timeout: u64,
+
+    /// Milliseconds without progress before a replica recovers another leader's instance
+    #[arg(long, default_value_t = 2000)]
+    recovery_timeout: u64,
+
+    /// Milliseconds between two heartbeats to every other replica
+    #[arg(long, default_value_t = 200)]
+    heartbeat_interval: u64,
+
+    /// Milliseconds without a heartbeat reply before a replica is suspected to have failed
+    #[arg(long, default_value_t = 1000)]
+    suspect_timeout: u64,
+
+    /// Thrifty mode: send PreAccept only to the closest fast quorum of replicas
+    #[arg(long, default_value_t = false)]
+    thrifty: bool,
+
+    /// Milliseconds to wait for the thrifty fast quorum before sending PreAccept to every replica
+    #[arg(long, default_value_t = 500)]
+    thrifty_timeout: u64,
+
+    /// Exchange execution traces with the other replicas and abort if a key was written in a different order
+    #[arg(long, default_value_t = false)]
+    check_order: bool,
+
+    /// Milliseconds between two checkpoints, instances executed by every replica are then removed from the log
+    #[arg(long, default_value_t = 1000)]
+    checkpoint_interval: u64,
+
+    /// Write-ahead log file: the replica persists its state there and rebuilds it on restart
+    #[arg(long)]
+    wal: Option<String>,
+
+    /// When the write-ahead log is forced to disk
+    #[arg(long, value_enum, default_value_t = FsyncPolicy::Every)]
+    fsync: FsyncPolicy,
+
+    /// Milliseconds between two fsyncs of the write-ahead log with the grouped policy
+    #[arg(long, default_value_t = 10)]
+    fsync_interval: u64,
+
+    /// Number of instances another replica must have executed ahead of this one before it requests a snapshot
+    #[arg(long, default_value_t = 1000)]
+    snapshot_threshold: u64,
+
+    /// Join a running cluster: the replica gets the configuration and the state from the other replicas
+    #[arg(long, default_value_t = false)]
+    join: bool,
+
+    /// Add the replica listening on this address to the configuration of the replica given with --connections
+    #[arg(long)]
+    add_replica: Option<String>,
+
+    /// Remove the replica with this ID from the configuration of the replica given with --connections
+    #[arg(long)]
+    remove_replica: Option<u8>,
+
+    /// File in which a generating client records when each of its requests was sent and answered, with the reply
+    #[arg(long)]
+    history: Option<String>,
+
+    /// Check that the client histories recorded in these files are linearizable, all other flags are ignored
+    #[arg(long, num_args = 1..)]
+    check_history: Vec<String>,
+
+    /// Fault injected into the messages this replica receives from the others, as fault:probability[:message type[:peer]] with fault drop, delay, duplicate or reorder (e.g. drop:0.1, duplicate:0.05:Commit, delay:0.2::127.0.0.1:8000)
+    #[arg(long, num_args = 1..)]
+    nemesis: Vec<Rule>,
+
+    /// JSON file of the faults to inject, e.g. {"delay": [50, 500], "rules": [{"fault": "drop", "probability": 0.1, "message": "Commit"}]}
+    #[arg(long)]
+    nemesis_file: Option<String>,
+
+    /// Minimum and maximum milliseconds by which the nemesis delays a delayed or duplicated message
+    #[arg(long, num_args = 2, value_names = ["MIN", "MAX"])]
+    nemesis_delay: Option<Vec<u64>>,
+
+    /// JSON file of the partitions to apply over time, e.g. [{"from": 10, "until": 20, "groups": [["127.0.0.1:6000"]]}] cuts the replica at 127.0.0.1:6000 off from the others from the 10th to the 20th second; with --simulate, the simulated replica i is at 10.0.0.i:7000
+    #[arg(long)]
+    partition_schedule: Option<String>,
+
+    /// Start of the partition schedule in seconds since the Unix epoch, the same for every replica of the cluster; when the replica starts if not provided
+    #[arg(long)]
+    partition_start: Option<u64>,
+
+    /// How the replicas and clients reach each other: tcp, unix (domain sockets in --socket-dir, for replicas and clients on one machine) or memory (a whole cluster of --n replicas and --sim-clients generating clients in this process, connected by channels, which checks the --history of the clients at the end)
+    #[arg(long, value_enum, default_value_t = TransportKind::Tcp)]
+    transport: TransportKind,
+
+    /// Directory of the Unix domain sockets, the socket of an address is <address>.sock in it, e.g. /tmp/127.0.0.1:6000.sock
+    #[arg(long, default_value = "/tmp")]
+    socket_dir: String,
+
+    /// Encoding of the messages sent over tcp or unix: binary, or json to read them when debugging; a replica or client decodes both
+    #[arg(long, value_enum, default_value_t = WireFormat::Binary)]
+    wire_format: WireFormat,
+
+    /// Client ID, the same ID must be used when a request is sent again; random if not provided
+    #[arg(long)]
+    client_id: Option<u64>,
+
+    /// Simulate --n replicas and their clients in this process on a virtual clock, instead of running a replica or a client
+    #[arg(long, default_value_t = false)]
+    simulate: bool,
+
+    /// Seed of the simulation, a run is replayed from its seed; random if not provided
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of simulations to run, with consecutive seeds, stopping at the first broken invariant
+    #[arg(long, default_value_t = 1)]
+    sim_runs: u64,
+
+    /// Virtual milliseconds during which the simulated clients send requests
+    #[arg(long, default_value_t = 10000)]
+    sim_time: u64,
+
+    /// Number of simulated clients
+    #[arg(long, default_value_t = 3)]
+    sim_clients: u16,
+
+    /// Minimum and maximum latency of a simulated message in milliseconds
+    #[arg(long, num_args = 2, value_names = ["MIN", "MAX"], default_values_t = [1, 10])]
+    latency: Vec<u64>,
+
+    /// Probability [0,1] that a simulated message is lost
+    #[arg(long, default_value_t = 0.0)]
+    drop: f64,
+
+    /// Probability [0,1] that a simulated message overtakes the earlier messages of its link
+    #[arg(long, default_value_t = 0.0)]
+    reorder: f64,
+
+    /// Probability [0,1] that a simulated message between replicas is delivered twice
+    #[arg(long, default_value_t = 0.0)]
+    duplicate: f64,
 }
 
 fn main() -> io::Result<()> {
     let cli = Cli::parse();
+    let client_id = cli.client_id.unwrap_or_else(rand::random);
+    let mut nemesis = match cli.nemesis_file.as_ref() {
+        Some(path) => NemesisConfig::load(path)?,
+        None => NemesisConfig::default(),
+    };
+    nemesis.rules.extend(cli.nemesis.iter().cloned());
+    if let Some(delay) = cli.nemesis_delay.as_ref() {
+        nemesis.delay = (delay[0], delay[1]);
+    }
+    if let Some(path) = cli.partition_schedule.as_ref() {
+        nemesis
+            .partitions
+            .extend(NemesisConfig::load_partitions(path)?);
+    }
+    if let Some(start) = cli.partition_start {
+        nemesis.start = Some(start * 1000);
+    }
+    let config = ReplicaConfig {
+        recovery_timeout: Duration::from_millis(cli.recovery_timeout),
+        heartbeat_interval: Duration::from_millis(cli.heartbeat_interval),
+        suspect_timeout: Duration::from_millis(cli.suspect_timeout),
+        thrifty: cli.thrifty,
+        thrifty_timeout: Duration::from_millis(cli.thrifty_timeout),
+        check_order: cli.check_order,
+        checkpoint_interval: Duration::from_millis(cli.checkpoint_interval),
+        wal: cli.wal.clone(),
+        fsync: cli.fsync,
+        fsync_interval: Duration::from_millis(cli.fsync_interval),
+        snapshot_threshold: cli.snapshot_threshold,
+        join: cli.join,
+        nemesis,
+        ..ReplicaConfig::default()
+    };
 
+    if !cli.check_history.is_empty() {
+        return check_histories(&cli.check_history);
+    }
+
+    if cli.simulate {
+        let first_seed = cli.seed.unwrap_or_else(rand::random);
+        for seed in first_seed..first_seed.saturating_add(cli.sim_runs) {
+            let sim_config = SimConfig {
+                seed,
+                n: cli.n,
+                clients: cli.sim_clients,
+                duration: Duration::from_millis(cli.sim_time),
+                latency: (
+                    Duration::from_millis(cli.latency[0]),
+                    Duration::from_millis(cli.latency[1]),
+                ),
+                drop: cli.drop,
+                reorder: cli.reorder,
+                duplicate: cli.duplicate,
+                conflict: cli.rate,
+                increments: cli.increments,
+                reads: cli.reads,
+                think_time: Duration::from_millis(cli.time_sleep),
+                request_timeout: Duration::from_millis(cli.request_timeout),
+                partitions: config.nemesis.partitions.clone(),
+            };
+            let mut simulation = Simulation::new(sim_config, config.clone());
+            simulation
+                .check()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            match simulation.run() {
+                Ok(report) => println!("{}", report),
+                Err(violation) => {
+                    println!("Seed {}: invariant broken {}", seed, violation);
+                    exit(1);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    match cli.transport {
+        TransportKind::Tcp => {
+            let transport = TcpTransport::new(cli.wire_format);
+            run(cli, config, client_id, transport)
+        }
+        TransportKind::Unix => {
+            let transport = UnixTransport::new(&cli.socket_dir, cli.wire_format);
+            run(cli, config, client_id, transport)
+        }
+        TransportKind::Memory => run_in_memory(cli, config),
+    }
+}
+
+fn check_histories(paths: &[String]) -> io::Result<()> {
+    let events = history::read_histories(paths)?;
+    match history::check_linearizable(&events) {
+        Ok(report) => println!("{}", report),
+        Err(violation) => {
+            println!("History is not linearizable: {}", violation);
+            exit(1);
+        }
+    }
+    Ok(())
+}
+
+// runs the replica or the client the arguments describe, which reaches the others over transport
+fn run<T: Transport>(
+    cli: Cli,
+    config: ReplicaConfig,
+    client_id: u64,
+    transport: T,
+) -> io::Result<()> {
     match (cli.save, cli.gen) {
         (Some(addr), _) => {
             let socket = SocketAddr::from_str(&addr).unwrap();
-            let _res = client::save_replica_state(socket);
+            let _res = client::save_replica_state(transport, socket);
         }
         (None, Some(addr)) => {
             let socket = SocketAddr::from_str(&addr).unwrap();
@@ -79,17 +347,62 @@ fn main() -> io::Result<()> {
                 Some(p) => SocketAddr::from_str(&p).unwrap(),
                 None => listen_socket,
             };
+            // the other replicas the client fails over to
+            let mut replicas = vec![socket];
+            for addr in cli.connections.iter() {
+                let replica = SocketAddr::from_str(addr).unwrap();
+                if !replicas.contains(&replica) {
+                    replicas.push(replica);
+                }
+            }
             let _res = client::generator_client(
-                socket,
+                transport,
+                replicas,
                 listen_socket,
                 public,
+                client_id,
                 cli.rate,
+                cli.increments,
+                cli.transfers,
+                cli.reads,
                 cli.time_sleep,
                 cli.experiment_time,
+                Duration::from_millis(cli.request_timeout),
+                cli.history,
+            );
+        }
+        (None, None) if cli.add_replica.is_some() || cli.remove_replica.is_some() => {
+            let listen_socket = match (cli.listener, cli.connections.len()) {
+                (Some(l), 1) => SocketAddr::from_str(&l).unwrap(),
+                _ => {
+                    println!("Reconfiguration needs one replica to connect to and an address to listen on for the reply");
+                    exit(0);
+                }
+            };
+            let socket = SocketAddr::from_str(&cli.connections[0]).unwrap();
+            let public = match cli.public_ip {
+                Some(p) => SocketAddr::from_str(&p).unwrap(),
+                None => listen_socket,
+            };
+            let add = cli.add_replica.map(|a| SocketAddr::from_str(&a).unwrap());
+            let _res = client::reconfigure(
+                transport,
+                socket,
+                listen_socket,
+                public,
+                client_id,
+                add,
+                cli.remove_replica,
             );
         }
         (None, None) => {
-            if (cli.debug_client == false) && cli.connections.len() == usize::from(cli.n - 1) {
+            // a joining replica takes the configuration of the cluster, whatever its size
+            if !cli.debug_client && !cli.join && (cli.n < 3 || cli.n.is_multiple_of(2)) {
+                println!("Number of replicas (--n) must be odd and at least 3");
+            } else if (cli.debug_client == false)
+                && (cli.connections.len() == usize::from(cli.n - 1)
+                    || (cli.join && !cli.connections.is_empty()))
+            {
                 match cli.listener {
                     Some(lst) => {
                         // debugging
@@ -114,8 +427,15 @@ fn main() -> io::Result<()> {
                                 };
                                 let listener = SocketAddr::from_str(&public_addr).unwrap();
                                 let local_addr = SocketAddr::from_str(&lst).unwrap();
-                                let mut replica = Replica::new(id, listener, local_addr, connections, cli.n);
-                                let _res = replica.start();
+                                let mut replica = Replica::<KvStore>::new(
+                                    id,
+                                    listener,
+                                    local_addr,
+                                    connections,
+                                    cli.n,
+                                    config,
+                                );
+                                let _res = replica.start(transport);
                             }
                             None => {
                                 println!("Need to specify replica id")
@@ -130,7 +450,7 @@ fn main() -> io::Result<()> {
                 println!("Number of connections does not match argument passed to --n (number of replicas)")
             } else if cli.connections.len() == 1 {
                 let socket = SocketAddr::from_str(&cli.connections[0]).unwrap();
-                let _res = client::debugging_client(socket);
+                let _res = client::debugging_client(transport, socket, client_id);
             } else {
                 println!("Client must connect to exactly one replica")
             }
@@ -139,3 +459,69 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+// runs --n replicas at 10.0.0.i:7000 and --sim-clients generating clients at 10.0.1.c:8000 in this
+// process, connected by channels; client c records its history in <--history>.c, and the
+// histories are checked once every client is done
+fn run_in_memory(cli: Cli, config: ReplicaConfig) -> io::Result<()> {
+    // the last byte of the address of a client is its number
+    let sim_clients = u8::try_from(cli.sim_clients).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("at most 255 clients run in memory, not {}", cli.sim_clients),
+        )
+    })?;
+    let transport = MemoryTransport::new();
+    let replicas: Vec<SocketAddr> = (1..=cli.n)
+        .map(|id| SocketAddr::from(([10, 0, 0, id], 7000)))
+        .collect();
+    for (id, addr) in (1..=cli.n).zip(replicas.iter()) {
+        let others = replicas.iter().filter(|a| *a != addr).cloned().collect();
+        let mut replica = Replica::<KvStore>::new(id, *addr, *addr, others, cli.n, config.clone());
+        let transport = transport.clone();
+        thread::spawn(move || replica.start(transport));
+    }
+    // give the replicas time to connect to each other
+    thread::sleep(Duration::from_secs(1));
+
+    let mut clients = Vec::new();
+    let mut histories = Vec::new();
+    for c in 1..=sim_clients {
+        let addr = SocketAddr::from(([10, 0, 1, c], 8000));
+        // client c sends to replica c first and fails over to the next ones
+        let mut targets = replicas.clone();
+        targets.rotate_left(usize::from(c - 1) % replicas.len());
+        let history = cli.history.as_ref().map(|path| format!("{}.{}", path, c));
+        histories.extend(history.clone());
+        let transport = transport.clone();
+        let (rate, increments, transfers, reads) =
+            (cli.rate, cli.increments, cli.transfers, cli.reads);
+        let (time_sleep, experiment_time) = (cli.time_sleep, cli.experiment_time);
+        let timeout = Duration::from_millis(cli.request_timeout);
+        clients.push(thread::spawn(move || {
+            client::generator_client(
+                transport,
+                targets,
+                addr,
+                addr,
+                rand::random(),
+                rate,
+                increments,
+                transfers,
+                reads,
+                time_sleep,
+                experiment_time,
+                timeout,
+                history,
+            )
+        }));
+    }
+    for client in clients {
+        client.join().unwrap()?;
+    }
+
+    if !histories.is_empty() {
+        return check_histories(&histories);
+    }
+    Ok(())
+}
@@ -1,184 +1,362 @@
+use crate::network::history::{now_micros, HistoryEvent, HistoryLog};
+use crate::network::kv::KvCommand;
 use crate::network::replica::ClientReply;
-use crate::network::replica::{ClientRequest, Event::ReceivedRequest, Event::SaveState};
+use crate::network::replica::{ClientRequest, Event, Event::ReceivedRequest, Event::SaveState};
+use crate::network::transport::Transport;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use smol::io::{AsyncBufReadExt, AsyncWriteExt};
+use smol::io::AsyncBufReadExt;
 use smol::stream::StreamExt;
-use smol::{future, io, Async, Unblock};
+use smol::{io, Unblock};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io::{stdout, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::process::exit;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-pub fn send_client_request(addr: SocketAddr, id: u64) -> io::Result<()> {
+pub fn save_replica_state<T: Transport>(transport: T, addr: SocketAddr) -> io::Result<()> {
     smol::block_on(async {
-        // Connect to the server and create async stdin and stdout.
-        let stream = Async::<TcpStream>::connect(addr).await?;
-
-        // Intro messages.
-        println!("Connected to {}", stream.get_ref().peer_addr()?);
-
-        let mut writer = &stream;
-
-        let mes = ClientRequest::Read("Hello".to_string(), addr, id);
-        let res = writer
-            .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-            .await;
-
-        res
+        let mut connection = transport.connect(addr).await?;
+        let mes: Event<KvCommand> = SaveState;
+        let _ = transport.send(&mut connection, &mes).await;
+        Ok(())
     })
 }
 
-pub fn save_replica_state(addr: SocketAddr) -> io::Result<()> {
+// sends a reconfiguration to the replica at addr and waits for its reply
+pub fn reconfigure<T: Transport>(
+    transport: T,
+    addr: SocketAddr,
+    listen_addr: SocketAddr,
+    public_addr: SocketAddr,
+    client_id: u64,
+    add: Option<SocketAddr>,
+    remove: Option<u8>,
+) -> io::Result<()> {
     smol::block_on(async {
-        let stream = Async::<TcpStream>::connect(addr).await?;
-        let mut writer = &stream;
-        let mes = SaveState;
-        let _ = writer
-            .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-            .await;
-        let _ = writer.write_all("\n".as_bytes()).await;
+        let listener = transport.bind(listen_addr).await?;
+        let mut connection = transport.connect(addr).await?;
+
+        let req: ClientRequest<KvCommand> = match (add, remove) {
+            (Some(new_addr), _) => {
+                ClientRequest::AddReplica(new_addr, public_addr, (client_id, 0, 0))
+            }
+            (None, Some(id)) => ClientRequest::RemoveReplica(id, public_addr, (client_id, 0, 0)),
+            (None, None) => return Ok(()),
+        };
+        let mes = ReceivedRequest(req);
+        let _ = transport.send(&mut connection, &mes).await;
+
+        let mut incoming = transport.accept(&listener).await?;
+        let reply: ClientReply = match transport.receive(&mut incoming).await {
+            Some(reply) => reply?,
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        match reply {
+            ClientReply::Reply(Some(id), _) if add.is_some() => println!("Added replica {}", id),
+            ClientReply::Reply(Some(id), _) => println!("Removed replica {}", id),
+            ClientReply::Reply(None, _) => println!("Replica is not a member"),
+        }
         Ok(())
     })
 }
 
-pub fn debugging_client(addr: SocketAddr) -> io::Result<()> {
+// parses a command of a transaction: r key, w key value, d key, i key amount, a key suffix
+// or c key expected new, with - as the expected value of an absent key
+fn parse_operation(line: &str) -> Option<KvCommand> {
+    let words: Vec<String> = line.split_whitespace().map(|w| w.to_string()).collect();
+    match words.iter().map(|w| w.as_str()).collect::<Vec<&str>>()[..] {
+        ["r", key] => Some(KvCommand::Read(key.to_string())),
+        ["w", key, value] => Some(KvCommand::Write(key.to_string(), value.to_string())),
+        ["d", key] => Some(KvCommand::Delete(key.to_string())),
+        ["i", key, amount] => Some(KvCommand::Increment(key.to_string(), amount.parse().ok()?)),
+        ["a", key, suffix] => Some(KvCommand::Append(key.to_string(), suffix.to_string())),
+        ["c", key, expected, new] => Some(KvCommand::Cas(
+            key.to_string(),
+            Some(expected.to_string()).filter(|e| e != "-"),
+            new.to_string(),
+        )),
+        _ => None,
+    }
+}
+
+pub fn debugging_client<T: Transport>(
+    transport: T,
+    addr: SocketAddr,
+    client_id: u64,
+) -> io::Result<()> {
     smol::block_on(async {
         // Connect to the server and create async stdin and stdout.
-        let stream = Async::<TcpStream>::connect(addr).await?;
+        let mut connection = transport.connect(addr).await?;
         let stdin = Unblock::new(std::io::stdin());
 
         // Intro messages.
-        println!("Connected to {}", stream.get_ref().peer_addr()?);
-        println!("My nickname: {}", stream.get_ref().local_addr()?);
+        println!("Connected to {}", addr);
 
-        let mut writer = &stream;
         let mut id: u64 = 0;
 
-        print!("Read or write? (r/w): ");
+        let menu = "Read, write, delete, increment, append, compare-and-swap or transaction? (r/w/d/i/a/c/t): ";
+        print!("{}", menu);
         let _ = stdout().flush();
 
         // read incoming lines until newlines
         let mut lines = io::BufReader::new(stdin).lines();
 
         let mut mode = "q".to_string();
-        let mut key: String = "".to_string();
+        // key and values entered so far for the current command
+        let mut args: Vec<String> = Vec::new();
 
         while let Some(line) = lines.next().await {
             match line {
                 Ok(line) => {
                     if mode == "q" {
-                        if line == "r" || line == "w" {
+                        if ["r", "w", "d", "i", "a", "c"].contains(&line.as_str()) {
                             mode = line;
                             print!("Key: ");
-                            let _ = stdout().flush();
+                        } else if line == "t" {
+                            mode = line;
+                            println!("One operation per line: r key, w key value, d key, i key amount, a key suffix, c key expected new (- if absent); empty line to send");
+                            print!("Operation: ");
                         } else {
-                            print!("Read or write? (r/w): ");
-                            let _ = stdout().flush();
+                            print!("{}", menu);
                         }
-                    } else if mode == "r" {
-                        mode = "q".to_string();
-                        let mes = ReceivedRequest(ClientRequest::Read(line, addr, id));
-                        let _ = writer
-                            .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-                            .await;
-                        let _ = writer.write_all("\n".as_bytes()).await;
-                        print!("Read or write? (r/w): ");
-                        let _ = stdout().flush();
-                    } else if mode == "w" {
-                        mode = "v".to_string();
-                        key = line.clone();
-                        print!("Value: ");
                         let _ = stdout().flush();
-                    } else {
-                        mode = "q".to_string();
-                        let mes =
-                            ReceivedRequest(ClientRequest::Write(key.clone(), line, addr, id));
-                        let _ = writer
-                            .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-                            .await;
-                        let _ = writer.write_all("\n".as_bytes()).await;
-                        print!("Read or write? (r/w): ");
+                        continue;
+                    }
+                    let req_id = (client_id, id, id);
+                    // a transaction collects operations until an empty line
+                    if mode == "t" && !line.is_empty() {
+                        if parse_operation(&line).is_none() {
+                            println!("Unknown operation");
+                        } else {
+                            args.push(line);
+                        }
+                        print!("Operation: ");
                         let _ = stdout().flush();
+                        continue;
+                    } else if mode != "t" {
+                        args.push(line);
+                    }
+                    let cmd = match (mode.as_str(), args.len()) {
+                        ("r", 1) => Some(KvCommand::Read(args[0].clone())),
+                        ("d", 1) => Some(KvCommand::Delete(args[0].clone())),
+                        ("w", 2) => Some(KvCommand::Write(args[0].clone(), args[1].clone())),
+                        ("a", 2) => Some(KvCommand::Append(args[0].clone(), args[1].clone())),
+                        ("i", 2) => match args[1].parse() {
+                            Ok(amount) => Some(KvCommand::Increment(args[0].clone(), amount)),
+                            Err(_) => {
+                                args.pop();
+                                print!("Amount must be an integer: ");
+                                let _ = stdout().flush();
+                                continue;
+                            }
+                        },
+                        // an empty expected value means the key must be absent
+                        ("c", 3) => Some(KvCommand::Cas(
+                            args[0].clone(),
+                            Some(args[1].clone()).filter(|v| !v.is_empty()),
+                            args[2].clone(),
+                        )),
+                        ("t", _) => Some(KvCommand::Transaction(
+                            args.iter().filter_map(|op| parse_operation(op)).collect(),
+                        )),
+                        _ => None,
+                    };
+                    let req = cmd.map(|cmd| ClientRequest::Command(cmd, addr, req_id));
+                    match req {
+                        Some(req) => {
+                            mode = "q".to_string();
+                            args.clear();
+                            let mes = ReceivedRequest(req);
+                            let _ = transport.send(&mut connection, &mes).await;
+                            id += 1;
+                            print!("{}", menu);
+                        }
+                        None => match (mode.as_str(), args.len()) {
+                            ("i", _) => print!("Amount: "),
+                            ("c", 1) => print!("Expected value (empty if absent): "),
+                            ("c", _) => print!("New value: "),
+                            _ => print!("Value: "),
+                        },
                     }
+                    let _ = stdout().flush();
                 }
                 Err(e) => {
                     println!("Client input error: {}", e);
                 }
             }
-            id += 1;
         }
         Ok(())
     })
 }
 
-pub fn generator_client(
+// request waiting for its reply: (request, time of the first send, time of the last send,
+// index of the replica it was last sent to, number of retries)
+pub type Pending = (ClientRequest<KvCommand>, Instant, Instant, usize, u32);
+
+// sends req to the replica at addr, connecting first if needed, returns false if the replica
+// cannot be reached
+async fn send_to<T: Transport>(
+    transport: &T,
+    connections: &mut HashMap<SocketAddr, T::Connection>,
     addr: SocketAddr,
+    req: &ClientRequest<KvCommand>,
+) -> bool {
+    if let Entry::Vacant(entry) = connections.entry(addr) {
+        match transport.connect(addr).await {
+            Ok(connection) => {
+                entry.insert(connection);
+            }
+            Err(_) => return false,
+        }
+    }
+    let connection = connections.get_mut(&addr).unwrap();
+    let mes = ReceivedRequest(req.clone());
+    if transport.send(connection, &mes).await.is_err() {
+        connections.remove(&addr);
+        return false;
+    }
+    true
+}
+
+// sends every request that has waited longer than timeout to the next replica, and retries
+// requests whose replica cannot be reached right away
+async fn retry_expired<T: Transport>(
+    transport: &T,
+    connections: &mut HashMap<SocketAddr, T::Connection>,
+    replicas: &[SocketAddr],
+    pending: &Arc<Mutex<HashMap<u64, Pending>>>,
+    timeout: Duration,
+) {
+    let expired: Vec<(u64, Pending)> = pending
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, (_, _, sent, _, _))| sent.elapsed() >= timeout)
+        .map(|(id, p)| (*id, p.clone()))
+        .collect();
+    for (id, (req, _, _, replica, _)) in expired {
+        let mut next = replica;
+        let mut tries: u32 = 0;
+        loop {
+            next = (next + 1) % replicas.len();
+            tries += 1;
+            if send_to(transport, connections, replicas[next], &req).await
+                || tries as usize == replicas.len()
+            {
+                break;
+            }
+        }
+        if let Some(p) = pending.lock().unwrap().get_mut(&id) {
+            p.2 = Instant::now();
+            p.3 = next;
+            p.4 += tries;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn generator_client<T: Transport>(
+    transport: T,
+    replicas: Vec<SocketAddr>,
     listen_addr: SocketAddr,
     public_addr: SocketAddr,
+    client_id: u64,
     conflict: f64,
+    increments: f64,
+    transfers: f64,
+    reads: f64,
     timesleep: u64,
     experiment_time: u64,
+    timeout: Duration,
+    history: Option<String>,
 ) -> io::Result<()> {
-    async fn print_incoming(
-        listener: Async<TcpListener>,
-        ts: Arc<Mutex<HashMap<u64, Instant>>>,
+    async fn print_incoming<T: Transport>(
+        transport: T,
+        listener: T::Listener,
+        ts: Arc<Mutex<HashMap<u64, Pending>>>,
+        retried: Arc<Mutex<Vec<(u64, u32)>>>,
+        client_id: u64,
+        history: Option<Arc<HistoryLog>>,
     ) -> io::Result<()> {
         loop {
             // Accept the next connection.
-            let (stream, _) = listener.accept().await?;
-            let ref mut line = String::new();
-            io::BufReader::new(stream).read_line(line).await?;
+            let mut incoming = transport.accept(&listener).await?;
 
             // println!("Reply received:");
-            // parse
-            let json: ClientReply = serde_json::from_str(line.as_str())?;
-
-            let mut ts_access = ts.lock().unwrap();
-            match json.clone() {
-                ClientReply::Reply(_, id) => match ts_access.get(&id).cloned() {
-                    Some(ms) => {
-                        ts_access.remove(&id);
-                        println!("{}ms", ms.elapsed().as_millis());
-                        // println!(
-                        //     "Req_ID: {}, reply: {:?}, duration: {}ms",
-                        //     id,
-                        //     x,
-                        //     ms.elapsed().as_millis()
-                        // )
+            while let Some(reply) = transport.receive(&mut incoming).await {
+                // the frame of a reply that cannot be decoded is skipped, a broken frame, e.g.
+                // from a replica that crashed, only ends its connection
+                let json: ClientReply = match reply {
+                    Ok(reply) => reply,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        println!("Skipped a reply: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("Reply connection failed: {}", e);
+                        break;
                     }
-                    None => (),
-                },
+                };
+
+                let mut ts_access = ts.lock().unwrap();
+                let ClientReply::Reply(x, id) = json.clone();
+                if let Some((_, ms, _, _, retries)) = ts_access.get(&id).cloned() {
+                    ts_access.remove(&id);
+                    if let Some(history) = history.as_ref() {
+                        history.log(&HistoryEvent::Complete(client_id, id, x, now_micros()));
+                    }
+                    println!("{}ms", ms.elapsed().as_millis());
+                    if retries > 0 {
+                        retried.lock().unwrap().push((id, retries));
+                    }
+                    // println!(
+                    //     "Req_ID: {}, reply: {:?}, duration: {}ms",
+                    //     id,
+                    //     x,
+                    //     ms.elapsed().as_millis()
+                    // )
+                }
+                drop(ts_access);
+                // println!("{:?}", json);
             }
-            drop(ts_access);
-            // println!("{:?}", json);
         }
     }
 
     smol::block_on(async {
         // Listen
-        let listener = Async::<TcpListener>::bind(listen_addr)?;
-        println!(
-            "Listening to connections on {}",
-            listener.get_ref().local_addr()?
-        );
-        let time_store: Arc<Mutex<HashMap<u64, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
-
-        smol::spawn(print_incoming(listener, time_store.clone())).detach();
-
-        // Connect to the server
-        let stream = Async::<TcpStream>::connect(addr).await?;
+        let listener = transport.bind(listen_addr).await?;
+        println!("Listening to connections on {}", listen_addr);
+        let time_store: Arc<Mutex<HashMap<u64, Pending>>> = Arc::new(Mutex::new(HashMap::new()));
+        let retried: Arc<Mutex<Vec<(u64, u32)>>> = Arc::new(Mutex::new(Vec::new()));
+        let history = match history {
+            Some(path) => Some(Arc::new(HistoryLog::create(&path)?)),
+            None => None,
+        };
+
+        smol::spawn(print_incoming(
+            transport.clone(),
+            listener,
+            time_store.clone(),
+            retried.clone(),
+            client_id,
+            history.clone(),
+        ))
+        .detach();
+
+        // Connect to the first replica, the others are only used when it fails or is slow
+        let connection = transport.connect(replicas[0]).await?;
 
         // Intro messages.
-        println!("Connected to {}", stream.get_ref().peer_addr()?);
+        println!("Connected to {}", replicas[0]);
 
-        let mut writer = &stream;
+        let mut connections: HashMap<SocketAddr, T::Connection> = HashMap::new();
+        connections.insert(replicas[0], connection);
 
         let fixed = "hello".to_string();
-        let write_percentage = 1.0;
+        let write_percentage = 1.0 - reads;
         let mut id: u64 = 0;
 
         let timer = Duration::from_secs(experiment_time);
@@ -197,6 +375,16 @@ pub fn generator_client(
                 wf <= write_percentage
             };
 
+            let increment_coin = {
+                let inf: f64 = rng.gen();
+                inf < increments
+            };
+
+            let transfer_coin = {
+                let tf: f64 = rng.gen();
+                tf < transfers
+            };
+
             if !conflict_coin {
                 // no conflict so sample new key
                 key = rng
@@ -206,75 +394,93 @@ pub fn generator_client(
                     .collect();
             }
 
-            let mut ts_access = time_store.lock().unwrap();
-
-            if write_coin {
-                let mes = ReceivedRequest(ClientRequest::Write(key.clone(), key, public_addr, id));
-                let _ = writer
-                    .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-                    .await;
-                let _ = writer.write_all("\n".as_bytes()).await;
+            // every request below the oldest one still waiting for its reply was answered
+            let acked = time_store
+                .lock()
+                .unwrap()
+                .keys()
+                .min()
+                .cloned()
+                .unwrap_or(id);
+            let req_id = (client_id, id, acked);
+            let req = if transfer_coin {
+                // moves one unit from key to another account, the balances always sum to 0
+                let to: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(8)
+                    .map(char::from)
+                    .collect();
+                let ops = vec![KvCommand::Increment(key, -1), KvCommand::Increment(to, 1)];
+                KvCommand::Transaction(ops)
+            } else if increment_coin {
+                KvCommand::Increment(key, 1)
+            } else if write_coin {
+                // every write has its own value, so that a read tells which write it saw
+                KvCommand::Write(key, format!("{}.{}", client_id, id))
             } else {
-                let mes = ReceivedRequest(ClientRequest::Read(key, public_addr, id));
-                let _ = writer
-                    .write_all(serde_json::to_string(&mes).ok().unwrap().as_bytes())
-                    .await;
-                let _ = writer.write_all("\n".as_bytes()).await;
+                KvCommand::Read(key)
+            };
+            if let Some(history) = history.as_ref() {
+                history.log(&HistoryEvent::Invoke(
+                    client_id,
+                    id,
+                    req.clone(),
+                    now_micros(),
+                ));
             }
-
-            ts_access.insert(id, Instant::now());
-            drop(ts_access);
+            let req = ClientRequest::Command(req, public_addr, req_id);
+
+            let now = Instant::now();
+            time_store
+                .lock()
+                .unwrap()
+                .insert(id, (req.clone(), now, now, 0, 0));
+            if !send_to(&transport, &mut connections, replicas[0], &req).await {
+                // try the other replicas right away
+                time_store.lock().unwrap().get_mut(&id).unwrap().2 = now - timeout;
+            }
+            retry_expired(
+                &transport,
+                &mut connections,
+                &replicas,
+                &time_store,
+                timeout,
+            )
+            .await;
 
             thread::sleep(Duration::from_millis(timesleep));
             id += 1;
 
             if Instant::now().duration_since(start_time) >= timer {
-                thread::sleep(Duration::from_secs(5));
+                // keep retrying the requests still waiting for a reply for a while
+                let end = Instant::now();
+                while end.elapsed() < Duration::from_secs(5)
+                    && !time_store.lock().unwrap().is_empty()
+                {
+                    retry_expired(
+                        &transport,
+                        &mut connections,
+                        &replicas,
+                        &time_store,
+                        timeout,
+                    )
+                    .await;
+                    thread::sleep(Duration::from_millis(50));
+                }
+                for (req_id, retries) in retried.lock().unwrap().iter() {
+                    println!("Req_ID: {:?} needed {} retries", req_id, retries);
+                }
                 let ts_access = time_store.lock().unwrap();
-                for (req_id, wait_time) in ts_access.clone().into_iter() {
+                for (req_id, (_, wait_time, _, _, retries)) in ts_access.clone().into_iter() {
                     println!(
-                        "Req_ID: {:?} not received, duration: {}ms",
+                        "Req_ID: {:?} not received after {} retries, duration: {}ms",
                         req_id,
+                        retries,
                         wait_time.elapsed().as_millis()
                     )
                 }
-                exit(0);
+                return Ok(());
             }
         }
     })
 }
-
-pub fn run_client(addr: SocketAddr) -> io::Result<()> {
-    smol::block_on(async {
-        // Connect to the server and create async stdin and stdout.
-        let stream = Async::<TcpStream>::connect(addr).await?;
-        let stdin = Unblock::new(std::io::stdin());
-        let mut stdout = Unblock::new(std::io::stdout());
-
-        // Intro messages.
-        println!("Connected to {}", stream.get_ref().peer_addr()?);
-        println!("My nickname: {}", stream.get_ref().local_addr()?);
-        println!("Type a message and hit enter!\n");
-
-        let reader = &stream;
-        let mut writer = &stream;
-
-        // Wait until the standard input is closed or the connection is closed.
-        future::race(
-            async {
-                let _ = writer.write_all("Client  ".as_bytes()).await;
-                let res = io::copy(stdin, &mut writer).await;
-                println!("Quit!");
-                res
-            },
-            async {
-                let res = io::copy(reader, &mut stdout).await;
-                println!("Server disconnected!");
-                res
-            },
-        )
-        .await?;
-
-        Ok(())
-    })
-}
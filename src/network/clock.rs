@@ -0,0 +1,39 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// source of the current time of a replica: the system clock, or the virtual clock of a
+// simulation that only moves when the simulator advances it
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    Real,
+    // Virtual(start of the simulation, virtual time elapsed since then)
+    Virtual(Instant, Arc<Mutex<Duration>>),
+}
+
+impl Clock {
+    // virtual clock at time 0, the clones of the clock share its time
+    pub fn new_virtual() -> Self {
+        Clock::Virtual(Instant::now(), Arc::new(Mutex::new(Duration::ZERO)))
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::Real => Instant::now(),
+            Clock::Virtual(start, elapsed) => *start + *elapsed.lock().unwrap(),
+        }
+    }
+
+    // time elapsed since t
+    pub fn since(&self, t: Instant) -> Duration {
+        self.now().saturating_duration_since(t)
+    }
+
+    // moves a virtual clock forward to time since the start of the simulation
+    pub fn advance_to(&self, time: Duration) {
+        if let Clock::Virtual(_, elapsed) = self {
+            let mut elapsed = elapsed.lock().unwrap();
+            *elapsed = (*elapsed).max(time);
+        }
+    }
+}
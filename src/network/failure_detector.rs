@@ -0,0 +1,216 @@
+use crate::network::clock::Clock;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// weight of a new round-trip sample in the smoothed round-trip time
+const RTT_ALPHA: f64 = 0.25;
+
+// Heartbeat based failure detector: the replica pings every peer periodically and a peer that
+// has not answered for `timeout` is suspected until it answers again.
+#[derive(Debug, Clone)]
+pub struct FailureDetector {
+    timeout: Duration,
+    clock: Clock,
+    round: u64,
+    // send time of the heartbeat rounds that may still be answered
+    sent: HashMap<u64, Instant>,
+    last_heard: HashMap<SocketAddr, Instant>,
+    // smoothed round-trip time of every peer that answered at least once
+    rtt: HashMap<SocketAddr, Duration>,
+    // replica ID -> address, learned from heartbeats
+    ids: HashMap<u8, SocketAddr>,
+    suspected: HashSet<SocketAddr>,
+}
+
+impl FailureDetector {
+    // every peer starts alive so that it gets one timeout to answer its first heartbeat
+    pub fn new(peers: &[SocketAddr], timeout: Duration, clock: Clock) -> Self {
+        let now = clock.now();
+        FailureDetector {
+            timeout,
+            clock,
+            round: 0,
+            sent: HashMap::new(),
+            last_heard: peers.iter().map(|p| (*p, now)).collect(),
+            rtt: HashMap::new(),
+            ids: HashMap::new(),
+            suspected: HashSet::new(),
+        }
+    }
+
+    // starts a new heartbeat round, returns its number to send in Ping
+    pub fn next_round(&mut self) -> u64 {
+        self.round += 1;
+        let now = self.clock.now();
+        let timeout = self.timeout;
+        self.sent.retain(|_, t| now.duration_since(*t) < timeout);
+        self.sent.insert(self.round, now);
+        self.round
+    }
+
+    // records a Pong (or any other sign of life) from peer
+    pub fn heard_from(&mut self, peer: SocketAddr, id: u8, round: Option<u64>) {
+        let now = self.clock.now();
+        self.last_heard.insert(peer, now);
+        self.ids.insert(id, peer);
+        self.suspected.remove(&peer);
+
+        if let Some(sent) = round.and_then(|r| self.sent.get(&r)) {
+            let sample = now.duration_since(*sent);
+            let rtt = match self.rtt.get(&peer) {
+                Some(rtt) => rtt.mul_f64(1.0 - RTT_ALPHA) + sample.mul_f64(RTT_ALPHA),
+                None => sample,
+            };
+            self.rtt.insert(peer, rtt);
+        }
+    }
+
+    // re-evaluates every peer, returns the peers that became suspected since the last call
+    pub fn update(&mut self) -> Vec<SocketAddr> {
+        let timeout = self.timeout;
+        let newly: Vec<SocketAddr> = self
+            .last_heard
+            .iter()
+            .filter(|(p, t)| self.clock.since(**t) > timeout && !self.suspected.contains(*p))
+            .map(|(p, _)| *p)
+            .collect();
+        self.suspected.extend(newly.iter().cloned());
+        newly
+    }
+
+    pub fn is_suspected(&self, peer: SocketAddr) -> bool {
+        match self.last_heard.get(&peer) {
+            Some(t) => self.clock.since(*t) > self.timeout,
+            None => true,
+        }
+    }
+
+    // a replica we never heard from is not suspected, we only know its address after a Pong
+    pub fn is_replica_suspected(&self, id: u8) -> bool {
+        match self.ids.get(&id) {
+            Some(peer) => self.is_suspected(*peer),
+            None => false,
+        }
+    }
+
+    pub fn alive(&self) -> Vec<SocketAddr> {
+        self.last_heard
+            .keys()
+            .filter(|p| !self.is_suspected(**p))
+            .cloned()
+            .collect()
+    }
+
+    pub fn suspected(&self) -> Vec<SocketAddr> {
+        self.last_heard
+            .keys()
+            .filter(|p| self.is_suspected(**p))
+            .cloned()
+            .collect()
+    }
+
+    pub fn rtt(&self, peer: SocketAddr) -> Option<Duration> {
+        self.rtt.get(&peer).cloned()
+    }
+
+    // the k peers to contact first: peers that are not suspected ordered by round-trip time,
+    // peers without a measurement after them and suspected peers last
+    pub fn closest(&self, peers: &[SocketAddr], k: usize) -> Vec<SocketAddr> {
+        let mut sorted: Vec<SocketAddr> = peers.to_vec();
+        sorted.sort_by_key(|p| (self.is_suspected(*p), self.rtt(*p).unwrap_or(Duration::MAX)));
+        sorted.truncate(k);
+        sorted
+    }
+
+    pub fn address(&self, id: u8) -> Option<SocketAddr> {
+        self.ids.get(&id).cloned()
+    }
+
+    // stops monitoring a replica that left the configuration, returns its address if known
+    pub fn remove_replica(&mut self, id: u8) -> Option<SocketAddr> {
+        let peer = self.ids.remove(&id)?;
+        self.last_heard.remove(&peer);
+        self.rtt.remove(&peer);
+        self.suspected.remove(&peer);
+        Some(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 7000))
+    }
+
+    #[test]
+    fn a_silent_peer_is_suspected_until_it_answers() {
+        let clock = Clock::new_virtual();
+        let timeout = Duration::from_millis(100);
+        let mut fd = FailureDetector::new(&[peer(2), peer(3)], timeout, clock.clone());
+
+        // exactly the timeout is not enough
+        clock.advance_to(timeout);
+        assert!(fd.update().is_empty());
+        fd.heard_from(peer(3), 3, None);
+
+        clock.advance_to(Duration::from_millis(150));
+        assert_eq!(fd.update(), vec![peer(2)]);
+        // reported once, and still suspected
+        assert!(fd.update().is_empty());
+        assert!(fd.is_suspected(peer(2)));
+        assert!(!fd.is_suspected(peer(3)));
+        // replica 2 never answered, its address is not known yet
+        assert!(!fd.is_replica_suspected(2));
+
+        let round = fd.next_round();
+        fd.heard_from(peer(2), 2, Some(round));
+        assert!(!fd.is_suspected(peer(2)));
+        assert_eq!(fd.suspected(), Vec::<SocketAddr>::new());
+
+        clock.advance_to(Duration::from_millis(300));
+        let mut newly = fd.update();
+        newly.sort();
+        assert_eq!(newly, vec![peer(2), peer(3)]);
+        assert!(fd.is_replica_suspected(2));
+    }
+
+    #[test]
+    fn closest_orders_by_round_trip_time() {
+        let clock = Clock::new_virtual();
+        let timeout = Duration::from_millis(100);
+        let peers = [peer(2), peer(3), peer(4), peer(5)];
+        let mut fd = FailureDetector::new(&peers, timeout, clock.clone());
+
+        // 4 answers after 10ms and 2 after 30ms, 3 never does
+        let round = fd.next_round();
+        clock.advance_to(Duration::from_millis(10));
+        fd.heard_from(peer(4), 4, Some(round));
+        clock.advance_to(Duration::from_millis(30));
+        fd.heard_from(peer(2), 2, Some(round));
+        assert_eq!(fd.rtt(peer(4)), Some(Duration::from_millis(10)));
+        assert_eq!(fd.rtt(peer(2)), Some(Duration::from_millis(30)));
+        assert_eq!(fd.rtt(peer(3)), None);
+        assert_eq!(fd.closest(&peers, 2), vec![peer(4), peer(2)]);
+
+        // 3, 4 and 5 are suspected, then 4 answers and 5 answers a new round within 1ms; 3 is
+        // still suspected and comes last
+        clock.advance_to(Duration::from_millis(120));
+        fd.update();
+        let round = fd.next_round();
+        fd.heard_from(peer(4), 4, None);
+        clock.advance_to(Duration::from_millis(121));
+        fd.heard_from(peer(5), 5, Some(round));
+        let closest = fd.closest(&peers, 4);
+        assert_eq!(closest[..2], [peer(5), peer(4)]);
+        assert_eq!(closest[2..], [peer(2), peer(3)]);
+
+        // the smoothed time moves a quarter of the way to each new sample
+        let round = fd.next_round();
+        clock.advance_to(Duration::from_millis(126));
+        fd.heard_from(peer(5), 5, Some(round));
+        assert_eq!(fd.rtt(peer(5)), Some(Duration::from_micros(2000)));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::network::kv::KvCommand;
+use crate::network::state_machine::Interference;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// event of a client history, times in microseconds (since the Unix epoch for a real client, so
+// that the histories of the clients of one machine can be merged)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HistoryEvent {
+    // Invoke(client ID, request number, command, time the request was first sent)
+    Invoke(u64, u64, KvCommand, u64),
+    // Complete(client ID, request number, reply, time the first reply was received)
+    Complete(u64, u64, Option<String>, u64),
+}
+
+// file a client appends its history to, one JSON event per line
+pub struct HistoryLog {
+    file: Mutex<File>,
+}
+
+impl HistoryLog {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(HistoryLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn log(&self, event: &HistoryEvent) {
+        let mut line = serde_json::to_string(event).unwrap();
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("Could not record {:?} in the history: {}", event, e);
+        }
+    }
+}
+
+// current time of a real client, in microseconds since the Unix epoch
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+// events of the history files of every client of a run
+pub fn read_histories(paths: &[String]) -> io::Result<Vec<HistoryEvent>> {
+    let mut events = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            events.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(events)
+}
+
+// (command, invocation time, completion time, reply); an operation that never completed may
+// take effect at any time after its invocation, or never, and may have replied anything
+type Operation = (KvCommand, u64, u64, Option<Option<String>>);
+
+// value of a key after op, None if op cannot take effect while the key holds value: a read
+// replies the value, a write replies the value it replaced
+fn apply(value: &Option<String>, op: &Operation) -> Option<Option<String>> {
+    let replied = |expected: &Option<String>| op.3.as_ref().is_none_or(|r| r == expected);
+    match &op.0 {
+        KvCommand::Read(_) if replied(value) => Some(value.clone()),
+        KvCommand::Write(_, new) if replied(value) => Some(Some(new.clone())),
+        _ => None,
+    }
+}
+
+// searches for an order of the operations on one key that respects real time and the replies
+// (Wing and Gong's algorithm with Lowe's memoization of the configurations already explored);
+// returns the longest prefix of such an order if there is none
+fn linearize(ops: &[Operation]) -> Result<(), Vec<usize>> {
+    // invocations and completions by time, an invocation first when they are simultaneous
+    let mut entries: Vec<(u64, bool, usize)> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| [(op.1, false, i), (op.2, true, i)])
+        .collect();
+    entries.sort();
+    let completion: HashMap<usize, usize> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, is_completion, _))| *is_completion)
+        .map(|(e, (_, _, op))| (*op, e))
+        .collect();
+
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    // doubly linked list of the entries not linearized yet, head is its sentinel
+    let n = entries.len();
+    let (head, end) = (n, n + 1);
+    let mut next: Vec<usize> = (1..n).chain([end, 0]).collect();
+    let mut prev: Vec<usize> = [head].into_iter().chain(0..n - 1).chain([end]).collect();
+    let unlink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = next[e];
+        if next[e] != end {
+            prev[next[e]] = prev[e];
+        }
+    };
+    let relink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = e;
+        if next[e] != end {
+            prev[next[e]] = e;
+        }
+    };
+
+    // when every write has its own value, a value that was overwritten never comes back: a
+    // write cannot be linearized while an operation that replied the current value is not, or
+    // the search would try every order of the writes that never completed
+    let mut values = HashSet::new();
+    let unique = ops.iter().all(|op| match &op.0 {
+        KvCommand::Write(_, value) => values.insert(value),
+        _ => true,
+    });
+    // reply -> operations not linearized yet that replied it
+    let mut needed: HashMap<Option<String>, usize> = HashMap::new();
+    for reply in ops.iter().filter_map(|op| op.3.clone()) {
+        *needed.entry(reply).or_default() += 1;
+    }
+    let overwrites =
+        |needed: &HashMap<Option<String>, usize>, value: &Option<String>, op: usize| {
+            let observed = usize::from(ops[op].3.as_ref() == Some(value));
+            needed.get(value).cloned().unwrap_or(0) > observed
+        };
+
+    let mut value: Option<String> = None;
+    // bit set of the linearized operations
+    let mut linearized: Vec<u64> = vec![0; ops.len().div_ceil(64)];
+    // (invocation entry, value before it, whether it left the value unchanged) of every
+    // linearized operation, in order
+    let mut stack: Vec<(usize, Option<String>, bool)> = Vec::new();
+    let mut explored: HashSet<(Vec<u64>, Option<String>)> = HashSet::new();
+    let mut longest: Vec<usize> = Vec::new();
+
+    let mut e = next[head];
+    while next[head] != end {
+        let (_, is_completion, op) = entries[e];
+        let mut backtrack = is_completion;
+        if !is_completion {
+            let fits = apply(&value, &ops[op]).filter(|new_value| {
+                !unique || *new_value == value || !overwrites(&needed, &value, op)
+            });
+            if let Some(new_value) = fits {
+                // an operation that leaves the value unchanged, such as a read, can always be
+                // linearized as soon as it is invoked and fits: if that fails, so does any other
+                // order from here, there is no alternative to try
+                let unchanged = new_value == value;
+                linearized[op / 64] |= 1 << (op % 64);
+                if explored.insert((linearized.clone(), new_value.clone())) {
+                    if let Some(reply) = &ops[op].3 {
+                        *needed.get_mut(reply).unwrap() -= 1;
+                    }
+                    stack.push((e, value, unchanged));
+                    value = new_value;
+                    unlink(&mut next, &mut prev, e);
+                    unlink(&mut next, &mut prev, completion[&op]);
+                    if stack.len() > longest.len() {
+                        longest = stack.iter().map(|(e, _, _)| entries[*e].2).collect();
+                    }
+                    e = next[head];
+                    continue;
+                }
+                linearized[op / 64] &= !(1 << (op % 64));
+                backtrack = unchanged;
+            }
+            e = next[e];
+        }
+        // an operation completed before it could be linearized, undo the last choice
+        while backtrack {
+            let (last, last_value, unchanged) = match stack.pop() {
+                Some(top) => top,
+                None => return Err(longest),
+            };
+            let op = entries[last].2;
+            linearized[op / 64] &= !(1 << (op % 64));
+            if let Some(reply) = &ops[op].3 {
+                *needed.get_mut(reply).unwrap() += 1;
+            }
+            value = last_value;
+            relink(&mut next, &mut prev, completion[&op]);
+            relink(&mut next, &mut prev, last);
+            e = next[last];
+            backtrack = unchanged;
+        }
+    }
+    Ok(())
+}
+
+// when every write of a key has its own value, a write replies the value of the write it
+// follows: two writes that replaced the same value cannot both be linearized, which the search
+// would only find out after trying every order
+fn forked_writes(ops: &[Operation]) -> Option<(usize, usize)> {
+    let mut values = HashSet::new();
+    let mut replaced: HashMap<&Option<String>, usize> = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        if let KvCommand::Write(_, value) = &op.0 {
+            if !values.insert(value) {
+                return None;
+            }
+            if let Some(reply) = &op.3 {
+                if let Some(j) = replaced.insert(reply, i) {
+                    return Some((j, i));
+                }
+            }
+        }
+    }
+    None
+}
+
+// checks that a history of reads and writes is linearizable, key by key; keys that other
+// commands touched are not checked. Returns a report, or the key and the operations on it
+// that have no linearization
+pub fn check_linearizable(events: &[HistoryEvent]) -> Result<String, String> {
+    // (client ID, request number) -> operation
+    let mut ops: HashMap<(u64, u64), Operation> = HashMap::new();
+    for event in events {
+        if let HistoryEvent::Invoke(client, number, cmd, time) = event {
+            ops.insert((*client, *number), (cmd.clone(), *time, u64::MAX, None));
+        }
+    }
+    for event in events {
+        if let HistoryEvent::Complete(client, number, reply, time) = event {
+            if let Some(op) = ops.get_mut(&(*client, *number)) {
+                if op.3.is_none() {
+                    op.2 = *time;
+                    op.3 = Some(reply.clone());
+                }
+            }
+        }
+    }
+
+    let mut by_key: HashMap<String, Vec<Operation>> = HashMap::new();
+    let mut skipped: HashSet<String> = HashSet::new();
+    let mut sorted: Vec<((u64, u64), Operation)> = ops.into_iter().collect();
+    sorted.sort_by_key(|(id, op)| (op.1, *id));
+    for (_, op) in sorted {
+        match &op.0 {
+            KvCommand::Read(key) | KvCommand::Write(key, _) => {
+                by_key.entry(key.clone()).or_default().push(op)
+            }
+            cmd => skipped.extend(cmd.keys()),
+        }
+    }
+    // an operation that never completed may not have taken effect: a read without a reply
+    // constrains nothing, nor does a write of a value no reply holds
+    let observed: HashSet<String> = by_key
+        .values()
+        .flatten()
+        .filter_map(|op| op.3.clone().flatten())
+        .collect();
+    for key_ops in by_key.values_mut() {
+        key_ops.retain(|op| match (&op.0, &op.3) {
+            (_, Some(_)) => true,
+            (KvCommand::Write(_, value), None) => observed.contains(value),
+            _ => false,
+        });
+    }
+    let mut keys: Vec<&String> = by_key.keys().filter(|k| !skipped.contains(*k)).collect();
+    keys.sort();
+
+    let mut checked = 0;
+    for key in keys.iter() {
+        let key_ops = &by_key[*key];
+        if let Some((i, j)) = forked_writes(key_ops) {
+            return Err(format!(
+                "key {}: {:?} invoked at {}us and {:?} invoked at {}us both replaced {:?}",
+                key,
+                key_ops[i].0,
+                key_ops[i].1,
+                key_ops[j].0,
+                key_ops[j].1,
+                key_ops[i].3.clone().flatten()
+            ));
+        }
+        if let Err(longest) = linearize(key_ops) {
+            let first = (0..key_ops.len()).find(|i| !longest.contains(i)).unwrap();
+            let (cmd, invoked, completed, reply) = &key_ops[first];
+            let outcome = match reply {
+                Some(reply) => format!("completed at {}us with {:?}", completed, reply),
+                None => "never completed".to_string(),
+            };
+            return Err(format!(
+                "key {}: no order of its {} operations respects real time and the replies, at most {} of them fit before {:?} invoked at {}us, {}",
+                key,
+                key_ops.len(),
+                longest.len(),
+                cmd,
+                invoked,
+                outcome
+            ));
+        }
+        checked += key_ops.len();
+    }
+    Ok(format!(
+        "History is linearizable: {} operations on {} keys checked, {} keys skipped for commands other than reads and writes",
+        checked,
+        keys.len(),
+        skipped.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(client: u64, value: &str, invoked: u64) -> HistoryEvent {
+        HistoryEvent::Invoke(
+            client,
+            0,
+            KvCommand::Write("x".to_string(), value.to_string()),
+            invoked,
+        )
+    }
+
+    fn read(client: u64, invoked: u64) -> HistoryEvent {
+        HistoryEvent::Invoke(client, 0, KvCommand::Read("x".to_string()), invoked)
+    }
+
+    fn complete(client: u64, reply: Option<&str>, completed: u64) -> HistoryEvent {
+        HistoryEvent::Complete(client, 0, reply.map(str::to_string), completed)
+    }
+
+    #[test]
+    fn concurrent_writes_are_linearizable() {
+        // b overlaps a and replaced it, then a read sees b
+        let events = vec![
+            write(1, "a", 0),
+            write(2, "b", 5),
+            complete(1, None, 10),
+            complete(2, Some("a"), 20),
+            read(3, 25),
+            complete(3, Some("b"), 30),
+        ];
+        check_linearizable(&events).unwrap();
+    }
+
+    #[test]
+    fn a_stale_read_is_not_linearizable() {
+        let events = vec![
+            write(1, "a", 0),
+            complete(1, None, 10),
+            write(2, "b", 20),
+            complete(2, Some("a"), 30),
+            read(3, 40),
+            complete(3, Some("a"), 50),
+        ];
+        let violation = check_linearizable(&events).unwrap_err();
+        assert!(violation.starts_with("key x:"), "{}", violation);
+    }
+
+    #[test]
+    fn writes_that_never_completed_take_effect_at_any_time() {
+        // 100 writes never completed, the reads see their values one after the other
+        let pending = 100;
+        let mut events: Vec<HistoryEvent> =
+            (0..pending).map(|i| write(i, &i.to_string(), i)).collect();
+        for i in 0..pending {
+            let client = pending + i;
+            events.push(read(client, 1000 + 2 * i));
+            events.push(complete(client, Some(&i.to_string()), 1001 + 2 * i));
+        }
+        check_linearizable(&events).unwrap();
+
+        // each write takes effect once, so its value cannot come back after the others
+        let client = 2 * pending;
+        events.push(read(client, 5000));
+        events.push(complete(client, Some("0"), 5001));
+        check_linearizable(&events).unwrap_err();
+    }
+}
@@ -0,0 +1,290 @@
+use crate::network::state_machine::{Access, Interference, StateMachine};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum KvCommand {
+    Read(String),
+    Write(String, String),
+    Delete(String),
+    // Increment(key, amount): adds amount to the integer stored under key, missing keys count
+    // as 0; increments of a key commute with each other
+    Increment(String, i64),
+    // Append(key, suffix): appends suffix to the value of key
+    Append(String, String),
+    // Cas(key, expected value, new value): sets the new value if key holds the expected one
+    // (None: key absent), replies the value key held before
+    Cas(String, Option<String>, String),
+    // Transaction(commands): executed atomically, in order; if one of its compare-and-swaps does
+    // not match, none of its commands is applied
+    Transaction(Vec<KvCommand>),
+}
+
+impl Interference for KvCommand {
+    fn keys(&self) -> Vec<String> {
+        match self {
+            KvCommand::Read(key)
+            | KvCommand::Write(key, _)
+            | KvCommand::Delete(key)
+            | KvCommand::Increment(key, _)
+            | KvCommand::Append(key, _)
+            | KvCommand::Cas(key, _, _) => vec![key.clone()],
+            KvCommand::Transaction(cmds) => {
+                let mut keys: Vec<String> = cmds.iter().flat_map(|cmd| cmd.keys()).collect();
+                keys.sort();
+                keys.dedup();
+                keys
+            }
+        }
+    }
+
+    // a transaction is a write on each of its keys
+    fn access(&self) -> Access {
+        match self {
+            KvCommand::Read(_) => Access::Read,
+            KvCommand::Increment(_, _) => Access::Commutative,
+            _ => Access::Write,
+        }
+    }
+}
+
+// name of the command and the first 4 characters of its (first) key
+impl fmt::Display for KvCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KvCommand::Read(_) => "Read-",
+            KvCommand::Write(_, _) => "Write",
+            KvCommand::Delete(_) => "Del--",
+            KvCommand::Increment(_, _) => "Incr-",
+            KvCommand::Append(_, _) => "Apnd-",
+            KvCommand::Cas(_, _, _) => "CAS--",
+            KvCommand::Transaction(_) => "Txn--",
+        };
+        let key = self.keys().first().cloned().unwrap_or_default();
+        let short: String = format!("{:_<4}", key).chars().take(4).collect();
+        write!(f, "{}[{}", name, short)
+    }
+}
+
+// key-value store replicated by default
+#[derive(Debug, Default)]
+pub struct KvStore {
+    dict: HashMap<String, String>,
+}
+
+impl KvStore {
+    // runs cmd over the values written so far by the transaction it belongs to, which shadow
+    // dict; returns the reply and false if a compare-and-swap did not match. Increments reply
+    // nothing, so that replicas executing them in different orders send the same replies, and
+    // leave a value that is not an integer unchanged
+    fn run(
+        &self,
+        cmd: &KvCommand,
+        values: &mut HashMap<String, Option<String>>,
+    ) -> (Option<String>, bool) {
+        let key = match cmd {
+            // replies the result of every command, or nothing if the transaction aborted
+            KvCommand::Transaction(cmds) => {
+                let mut results = Vec::new();
+                let mut matched = true;
+                for cmd in cmds {
+                    let (res, ok) = self.run(cmd, values);
+                    results.push(res);
+                    matched &= ok;
+                }
+                let reply = matched.then(|| serde_json::to_string(&results).unwrap());
+                return (reply, matched);
+            }
+            _ => cmd.keys().remove(0),
+        };
+        let value = match values.get(&key) {
+            Some(value) => value.clone(),
+            None => self.dict.get(&key).cloned(),
+        };
+
+        match cmd {
+            KvCommand::Write(_, val) => {
+                values.insert(key, Some(val.clone()));
+            }
+            KvCommand::Delete(_) => {
+                values.insert(key, None);
+            }
+            KvCommand::Increment(_, amount) => {
+                if let Some(old) = value.as_ref().map_or(Some(0), |v| v.parse::<i64>().ok()) {
+                    values.insert(key, Some(old.wrapping_add(*amount).to_string()));
+                }
+                return (None, true);
+            }
+            KvCommand::Append(_, suffix) => {
+                let new = format!("{}{}", value.clone().unwrap_or_default(), suffix);
+                values.insert(key, Some(new));
+            }
+            KvCommand::Cas(_, expected, new) => {
+                if value != *expected {
+                    return (value, false);
+                }
+                values.insert(key, Some(new.clone()));
+            }
+            _ => (),
+        }
+        (value, true)
+    }
+}
+
+impl StateMachine for KvStore {
+    type Command = KvCommand;
+
+    fn apply(&mut self, cmd: &KvCommand) -> Option<String> {
+        let mut values = HashMap::new();
+        let (reply, matched) = self.run(cmd, &mut values);
+        if matched {
+            for (key, value) in values {
+                match value {
+                    Some(value) => self.dict.insert(key, value),
+                    None => self.dict.remove(&key),
+                };
+            }
+        }
+        reply
+    }
+
+    fn snapshot(&self) -> String {
+        serde_json::to_string(&self.dict).unwrap()
+    }
+
+    fn restore(snapshot: &str) -> Self {
+        KvStore {
+            dict: serde_json::from_str(snapshot).unwrap(),
+        }
+    }
+}
+
+// sorted pairs of the store
+impl fmt::Display for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sorted_dict: Vec<(String, String)> = self.dict.clone().into_iter().collect();
+        sorted_dict.sort();
+        write!(f, "{:?}", sorted_dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(key: &str, value: &str) -> KvCommand {
+        KvCommand::Write(key.to_string(), value.to_string())
+    }
+
+    // the relation the replica derives from keys and access
+    fn interfere(a: &KvCommand, b: &KvCommand) -> bool {
+        let shared = a.keys().iter().any(|key| b.keys().contains(key));
+        let both = |access| a.access() == access && b.access() == access;
+        shared && !both(Access::Read) && !both(Access::Commutative)
+    }
+
+    #[test]
+    fn deleting_a_missing_key_replies_nothing() {
+        let mut store = KvStore::default();
+        assert_eq!(store.apply(&KvCommand::Delete("a".to_string())), None);
+        store.apply(&write("a", "1"));
+        assert_eq!(
+            store.apply(&KvCommand::Delete("a".to_string())),
+            Some("1".to_string())
+        );
+        assert_eq!(store.apply(&KvCommand::Read("a".to_string())), None);
+    }
+
+    #[test]
+    fn increments_add_to_integers_only() {
+        let mut store = KvStore::default();
+        assert_eq!(store.apply(&KvCommand::Increment("n".to_string(), 2)), None);
+        store.apply(&KvCommand::Increment("n".to_string(), 3));
+        assert_eq!(
+            store.apply(&KvCommand::Read("n".to_string())),
+            Some("5".to_string())
+        );
+
+        store.apply(&write("s", "abc"));
+        assert_eq!(store.apply(&KvCommand::Increment("s".to_string(), 1)), None);
+        assert_eq!(
+            store.apply(&KvCommand::Read("s".to_string())),
+            Some("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn appends_extend_the_value() {
+        let mut store = KvStore::default();
+        assert_eq!(
+            store.apply(&KvCommand::Append("a".to_string(), "x".to_string())),
+            None
+        );
+        assert_eq!(
+            store.apply(&KvCommand::Append("a".to_string(), "y".to_string())),
+            Some("x".to_string())
+        );
+        assert_eq!(
+            store.apply(&KvCommand::Read("a".to_string())),
+            Some("xy".to_string())
+        );
+    }
+
+    #[test]
+    fn compare_and_swap_sets_only_a_matching_value() {
+        let mut store = KvStore::default();
+        let cas = |expected: Option<&str>, new: &str| {
+            KvCommand::Cas(
+                "a".to_string(),
+                expected.map(str::to_string),
+                new.to_string(),
+            )
+        };
+        assert_eq!(store.apply(&cas(None, "1")), None);
+        assert_eq!(store.apply(&cas(Some("2"), "3")), Some("1".to_string()));
+        assert_eq!(
+            store.apply(&KvCommand::Read("a".to_string())),
+            Some("1".to_string())
+        );
+        assert_eq!(store.apply(&cas(Some("1"), "3")), Some("1".to_string()));
+        assert_eq!(
+            store.apply(&KvCommand::Read("a".to_string())),
+            Some("3".to_string())
+        );
+    }
+
+    #[test]
+    fn increments_commute_only_with_each_other() {
+        let incr = KvCommand::Increment("n".to_string(), 1);
+        let read = KvCommand::Read("n".to_string());
+        assert!(!interfere(&incr, &KvCommand::Increment("n".to_string(), 5)));
+        assert!(interfere(&incr, &read));
+        assert!(interfere(&incr, &write("n", "0")));
+        assert!(!interfere(&read, &read));
+        assert!(!interfere(&incr, &KvCommand::Increment("m".to_string(), 1)));
+    }
+
+    #[test]
+    fn an_aborted_transaction_writes_nothing() {
+        let mut store = KvStore::default();
+        store.apply(&write("b", "1"));
+        // the write to a comes before the compare-and-swap of b that does not match
+        let txn = KvCommand::Transaction(vec![
+            write("a", "1"),
+            KvCommand::Increment("c".to_string(), 1),
+            KvCommand::Cas("b".to_string(), Some("2".to_string()), "3".to_string()),
+            KvCommand::Delete("b".to_string()),
+        ]);
+        assert_eq!(store.apply(&txn), None);
+        assert_eq!(store.to_string(), r#"[("b", "1")]"#);
+
+        let txn = KvCommand::Transaction(vec![
+            write("a", "1"),
+            KvCommand::Cas("b".to_string(), Some("1".to_string()), "3".to_string()),
+            KvCommand::Read("a".to_string()),
+        ]);
+        assert_eq!(store.apply(&txn), Some(r#"[null,"1","1"]"#.to_string()));
+        assert_eq!(store.to_string(), r#"[("a", "1"), ("b", "3")]"#);
+    }
+}
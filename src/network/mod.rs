@@ -1,2 +1,13 @@
+pub mod failure_detector;
+pub mod order_checker;
 pub mod replica;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod wal;
+pub mod state_machine;
+pub mod kv;
+pub mod protocol;
+pub mod clock;
+pub mod simulator;
+pub mod history;
+pub mod nemesis;
+pub mod transport;
\ No newline at end of file
@@ -0,0 +1,369 @@
+use crate::network::replica::Event;
+use clap::ValueEnum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use smol::Timer;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// fault injected into a message received from another replica
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fault {
+    // the message is lost
+    Drop,
+    // the message is delivered after a random delay
+    Delay,
+    // the message is delivered, and a copy of it after a random delay
+    Duplicate,
+    // the message is held back until the next message from the same peer is delivered, or for
+    // the longest delay if none comes
+    Reorder,
+}
+
+// injects fault with probability into the messages of one type from one peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub fault: Fault,
+    pub probability: f64,
+    // type of the messages it applies to, e.g. Commit; every type if None
+    #[serde(default)]
+    pub message: Option<String>,
+    // replica whose messages it applies to; every replica if None
+    #[serde(default)]
+    pub peer: Option<SocketAddr>,
+}
+
+// fault:probability[:message type[:peer]], e.g. drop:0.1, duplicate:0.05:Commit or
+// delay:0.2::127.0.0.1:8000
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(4, ':').collect();
+        if parts.len() < 2 {
+            return Err(format!("{} is not fault:probability[:message[:peer]]", s));
+        }
+        let fault = Fault::from_str(parts[0], true)?;
+        let probability: f64 = parts[1]
+            .parse()
+            .map_err(|_| format!("{} is not a probability", parts[1]))?;
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!("{} is not a probability", parts[1]));
+        }
+        let message = parts
+            .get(2)
+            .filter(|m| !m.is_empty())
+            .map(|m| m.to_string());
+        let peer = match parts.get(3) {
+            Some(peer) => Some(
+                SocketAddr::from_str(peer).map_err(|_| format!("{} is not an address", peer))?,
+            ),
+            None => None,
+        };
+        Ok(Rule {
+            fault,
+            probability,
+            message,
+            peer,
+        })
+    }
+}
+
+// the replicas are split into groups that cannot reach each other from one time to another, in
+// seconds since the start of the schedule; replicas in no group form one more group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub from: f64,
+    pub until: f64,
+    pub groups: Vec<Vec<SocketAddr>>,
+}
+
+impl Partition {
+    // index of the group of addr
+    fn group(&self, addr: SocketAddr) -> usize {
+        self.groups
+            .iter()
+            .position(|group| group.contains(&addr))
+            .unwrap_or(self.groups.len())
+    }
+
+    // the partition separates a from b, elapsed seconds after the start of the schedule
+    pub fn separates(&self, elapsed: f64, a: SocketAddr, b: SocketAddr) -> bool {
+        self.from <= elapsed && elapsed < self.until && self.group(a) != self.group(b)
+    }
+}
+
+// faults a replica injects into the messages it receives from the other replicas
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NemesisConfig {
+    pub rules: Vec<Rule>,
+    // a delayed or duplicated message arrives between these two delays, in milliseconds
+    pub delay: (u64, u64),
+    pub partitions: Vec<Partition>,
+    // start of the partition schedule in milliseconds since the Unix epoch, shared by the
+    // replicas of a cluster; the start of the replica if None
+    pub start: Option<u64>,
+}
+
+impl Default for NemesisConfig {
+    fn default() -> Self {
+        NemesisConfig {
+            rules: Vec::new(),
+            delay: (10, 200),
+            partitions: Vec::new(),
+            start: None,
+        }
+    }
+}
+
+impl NemesisConfig {
+    // reads a configuration file, e.g.
+    // {"delay": [50, 500], "rules": [{"fault": "drop", "probability": 0.1, "message": "Commit"}]}
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // reads a partition schedule, e.g. replica 1 cut off from the others from 10 to 20 seconds:
+    // [{"from": 10, "until": 20, "groups": [["127.0.0.1:6000"]]}]
+    pub fn load_partitions(path: &str) -> io::Result<Vec<Partition>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // the partition schedule cuts the link between addr and peer now
+    fn partitioned(&self, addr: SocketAddr, peer: SocketAddr) -> bool {
+        let start = match self.start {
+            Some(start) => Duration::from_millis(start),
+            None => return false,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let elapsed = now.saturating_sub(start).as_secs_f64();
+        self.partitions
+            .iter()
+            .any(|p| p.separates(elapsed, addr, peer))
+    }
+}
+
+// (number of the hold, message) held back by a reorder
+pub type Held<C> = Arc<Mutex<Option<(u64, Event<C>)>>>;
+
+// fault injection on the messages of one connection
+pub struct Nemesis<C> {
+    config: NemesisConfig,
+    // address of the replica the faults are injected at
+    addr: SocketAddr,
+    // replica at the other end of the connection, learned from the first message that names it
+    peer: Option<SocketAddr>,
+    // the link to peer is cut by a partition
+    cut: bool,
+    // message held back by a reorder, delivered after the next one, and the number of the
+    // hold so that a late flush does not release a later one
+    held: Held<C>,
+    holds: u64,
+    // a message was held back since the last flush_held
+    flush_due: bool,
+}
+
+impl<C: Clone> Nemesis<C> {
+    pub fn new(config: NemesisConfig, addr: SocketAddr) -> Self {
+        Nemesis {
+            config,
+            addr,
+            peer: None,
+            cut: false,
+            held: Arc::new(Mutex::new(None)),
+            holds: 0,
+            flush_due: false,
+        }
+    }
+
+    // a matching rule fires, by a draw of its probability
+    fn fires(&self, fault: Fault, name: &str, peer: Option<SocketAddr>) -> bool {
+        self.config.rules.iter().any(|rule| {
+            rule.fault == fault
+                && rule.message.as_deref().is_none_or(|m| m == name)
+                && rule.peer.is_none_or(|p| Some(p) == peer)
+                && rand::thread_rng().gen::<f64>() < rule.probability
+        })
+    }
+
+    fn delay(&self) -> Duration {
+        let (min, max) = self.config.delay;
+        Duration::from_millis(rand::thread_rng().gen_range(min..=max.max(min)))
+    }
+
+    // events to deliver for a received event, each after its delay, in order; events that do
+    // not come from another replica are left alone
+    pub fn inject(&mut self, event: Event<C>) -> Vec<(Duration, Event<C>)> {
+        if !event.from_replica() {
+            return vec![(Duration::ZERO, event)];
+        }
+        let name = event.name();
+        let peer = event.peer().or(self.peer);
+        self.peer = peer;
+        let from = peer.map_or("a replica".to_string(), |p| p.to_string());
+
+        // messages across a partition are lost until it heals
+        let cut = peer.is_some_and(|peer| self.config.partitioned(self.addr, peer));
+        if cut != self.cut {
+            self.cut = cut;
+            match cut {
+                true => println!("Partitioned from {}", from),
+                false => println!("Partition from {} healed", from),
+            }
+        }
+        if cut {
+            return Vec::new();
+        }
+        if self.config.rules.is_empty() {
+            return vec![(Duration::ZERO, event)];
+        }
+
+        if self.fires(Fault::Drop, name, peer) {
+            println!("Nemesis dropped {} from {}", name, from);
+            return Vec::new();
+        }
+        let mut deliveries = Vec::new();
+        if self.fires(Fault::Duplicate, name, peer) {
+            println!("Nemesis duplicated {} from {}", name, from);
+            deliveries.push((self.delay(), event.clone()));
+        }
+        if self.fires(Fault::Delay, name, peer) {
+            println!("Nemesis delayed {} from {}", name, from);
+            deliveries.insert(0, (self.delay(), event));
+        } else if self.held.lock().unwrap().is_none() && self.fires(Fault::Reorder, name, peer) {
+            println!("Nemesis held back {} from {}", name, from);
+            self.holds += 1;
+            *self.held.lock().unwrap() = Some((self.holds, event));
+            self.flush_due = true;
+        } else {
+            deliveries.insert(0, (Duration::ZERO, event));
+            if let Some((_, held)) = self.held.lock().unwrap().take() {
+                deliveries.insert(1, (Duration::ZERO, held));
+            }
+        }
+        deliveries
+    }
+
+    // once a message was held back: waits for the longest delay and returns the message if no
+    // later one released it in the meantime
+    pub fn flush_held(&mut self) -> Option<impl Future<Output = Option<Event<C>>>> {
+        if !std::mem::take(&mut self.flush_due) {
+            return None;
+        }
+        let (held, hold) = (self.held.clone(), self.holds);
+        let (min, max) = self.config.delay;
+        let wait = Duration::from_millis(max.max(min));
+        Some(async move {
+            Timer::after(wait).await;
+            let mut held = held.lock().unwrap();
+            match held.take() {
+                Some((h, event)) if h == hold => Some(event),
+                other => {
+                    *held = other;
+                    None
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn ping(number: u64) -> Event<()> {
+        Event::Ping(addr(7000), 2, number)
+    }
+
+    fn number(event: &Event<()>) -> u64 {
+        match event {
+            Event::Ping(_, _, number) => *number,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        let rule = Rule::from_str("drop:0.1").unwrap();
+        assert_eq!((rule.fault, rule.probability), (Fault::Drop, 0.1));
+        assert_eq!((rule.message, rule.peer), (None, None));
+
+        let rule = Rule::from_str("duplicate:0.05:Commit").unwrap();
+        assert_eq!(rule.fault, Fault::Duplicate);
+        assert_eq!(rule.message.as_deref(), Some("Commit"));
+
+        // an address has a colon of its own
+        let rule = Rule::from_str("delay:0.2::127.0.0.1:8000").unwrap();
+        assert_eq!((rule.message, rule.peer), (None, Some(addr(8000))));
+
+        for invalid in [
+            "drop",
+            "explode:0.1",
+            "drop:x",
+            "drop:1.5",
+            "delay:0.2::nowhere",
+        ] {
+            assert!(Rule::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn a_partition_schedule_is_loaded() {
+        let path = std::env::temp_dir().join(format!("partitions-{}", std::process::id()));
+        let schedule = r#"[{"from": 10, "until": 20, "groups": [["127.0.0.1:6000"]]}]"#;
+        fs::write(&path, schedule).unwrap();
+        let partitions = NemesisConfig::load_partitions(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(partitions.len(), 1);
+        let partition = &partitions[0];
+        // the replicas in no group form a group of their own
+        assert!(partition.separates(15.0, addr(6000), addr(6001)));
+        assert!(!partition.separates(15.0, addr(6001), addr(6002)));
+        assert!(!partition.separates(5.0, addr(6000), addr(6001)));
+        assert!(!partition.separates(20.0, addr(6000), addr(6001)));
+    }
+
+    fn reordering() -> Nemesis<()> {
+        let config = NemesisConfig {
+            rules: vec![Rule::from_str("reorder:1").unwrap()],
+            delay: (1, 1),
+            ..NemesisConfig::default()
+        };
+        Nemesis::new(config, addr(6000))
+    }
+
+    #[test]
+    fn a_held_message_follows_the_next_one() {
+        let mut nemesis = reordering();
+        assert!(nemesis.inject(ping(1)).is_empty());
+        let flush = nemesis.flush_held().unwrap();
+        let delivered: Vec<u64> = nemesis
+            .inject(ping(2))
+            .iter()
+            .map(|d| number(&d.1))
+            .collect();
+        assert_eq!(delivered, [2, 1]);
+        assert!(smol::block_on(flush).is_none());
+    }
+
+    #[test]
+    fn a_held_message_is_flushed_when_no_other_comes() {
+        let mut nemesis = reordering();
+        assert!(nemesis.inject(ping(1)).is_empty());
+        let flush = nemesis.flush_held().unwrap();
+        assert!(nemesis.flush_held().is_none());
+        assert_eq!(smol::block_on(flush).as_ref().map(number), Some(1));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::network::replica::Instance;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// instances a snapshot reflects: replica -> every instance of that replica up to this number,
+// and the instances above it
+pub type Coverage = (HashMap<u8, u64>, HashSet<Instance>);
+
+fn covers(coverage: Option<&Coverage>, ins: &Instance) -> bool {
+    match coverage {
+        Some((prefix, executed)) => {
+            ins.1 <= prefix.get(&ins.0).cloned().unwrap_or(0) || executed.contains(ins)
+        }
+        None => false,
+    }
+}
+
+// (number of the first local write after the snapshot, snapshot) of the last snapshot a replica
+// installed
+pub type TraceReset = (u64, Coverage);
+
+// Checks that replicas write every key in the same order: the writes of a key that two replicas
+// both executed come in the same order on both. Reads and writes to different keys may
+// legitimately execute in different orders. Local writes are numbered in the order they are
+// shared, so that a trace that is lost, duplicated or reordered on its way to a peer only leaves
+// a gap there. A replica that installed a snapshot only traces the writes after it, so the
+// writes the snapshot covers are left out of the comparison on both sides.
+#[derive(Debug, Clone, Default)]
+pub struct OrderChecker {
+    // number of the next local write
+    next: u64,
+    local: HashMap<String, Vec<Instance>>,
+    reset: Option<TraceReset>,
+    // peer -> key -> number of the write on the peer -> write
+    peers: HashMap<u8, HashMap<String, BTreeMap<u64, Instance>>>,
+    peers_reset: HashMap<u8, TraceReset>,
+}
+
+impl OrderChecker {
+    pub fn new() -> Self {
+        OrderChecker::default()
+    }
+
+    // records local writes, after the snapshot installed since the last ones if any; returns
+    // the number of the first one
+    pub fn record_local(&mut self, writes: &[(Instance, String)], reset: Option<Coverage>) -> u64 {
+        // the local trace starts over after a snapshot
+        if let Some(coverage) = reset {
+            self.local.clear();
+            self.reset = Some((self.next, coverage));
+        }
+        let from = self.next;
+        for (ins, key) in writes {
+            self.local.entry(key.clone()).or_default().push(*ins);
+        }
+        self.next += writes.len() as u64;
+        from
+    }
+
+    // last snapshot installed here, sent with every trace in case an earlier one was lost
+    pub fn reset(&self) -> Option<TraceReset> {
+        self.reset.clone()
+    }
+
+    // records writes executed by a peer, numbered from `from` on, and compares every key they
+    // touch with the local order; writes already recorded or before the last snapshot the peer
+    // installed are ignored
+    pub fn record_peer(
+        &mut self,
+        peer: u8,
+        from: u64,
+        writes: &[(Instance, String)],
+        reset: Option<TraceReset>,
+    ) -> Result<(), String> {
+        let traces = self.peers.entry(peer).or_default();
+        if let Some((at, coverage)) = reset {
+            let newer = self
+                .peers_reset
+                .get(&peer)
+                .is_none_or(|(last, _)| at > *last);
+            if newer {
+                for trace in traces.values_mut() {
+                    trace.retain(|number, _| *number >= at);
+                }
+                self.peers_reset.insert(peer, (at, coverage));
+            }
+        }
+        let start = self.peers_reset.get(&peer).map_or(0, |(at, _)| *at);
+        for (number, (ins, key)) in (from..).zip(writes) {
+            if number >= start {
+                traces.entry(key.clone()).or_default().insert(number, *ins);
+            }
+        }
+
+        let ours_covered = self.reset.as_ref().map(|(_, coverage)| coverage);
+        let theirs_covered = self.peers_reset.get(&peer).map(|(_, coverage)| coverage);
+        let compared = |trace: Vec<Instance>| -> Vec<Instance> {
+            trace
+                .into_iter()
+                .filter(|ins| !covers(ours_covered, ins) && !covers(theirs_covered, ins))
+                .collect()
+        };
+        for (_, key) in writes {
+            let theirs = match traces.get(key) {
+                Some(theirs) => compared(theirs.values().cloned().collect()),
+                None => continue,
+            };
+            let ours = match self.local.get(key) {
+                Some(ours) => compared(ours.clone()),
+                None => continue,
+            };
+            // the writes one side has not executed or recorded yet are not compared
+            let both: HashSet<Instance> = ours
+                .iter()
+                .filter(|w| theirs.contains(w))
+                .cloned()
+                .collect();
+            let ours: Vec<Instance> = ours.into_iter().filter(|w| both.contains(w)).collect();
+            let theirs: Vec<Instance> = theirs.into_iter().filter(|w| both.contains(w)).collect();
+            if let Some(i) = (0..ours.len()).find(|i| ours[*i] != theirs[*i]) {
+                return Err(format!(
+                    "wrote key {} in a different order than replica {}: {:?} then {:?} here, {:?} there",
+                    key,
+                    peer,
+                    &ours[..i],
+                    ours[i],
+                    theirs[i],
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writes(instances: &[Instance]) -> Vec<(Instance, String)> {
+        instances
+            .iter()
+            .map(|ins| (*ins, "x".to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn different_orders_are_reported() {
+        let mut checker = OrderChecker::new();
+        checker.record_local(&writes(&[(1, 1), (2, 1)]), None);
+        assert!(checker
+            .record_peer(2, 0, &writes(&[(2, 1), (1, 1)]), None)
+            .is_err());
+    }
+
+    #[test]
+    fn duplicated_and_reordered_traces_are_recorded_once() {
+        let mut checker = OrderChecker::new();
+        checker.record_local(&writes(&[(1, 1), (2, 1), (3, 1), (4, 1)]), None);
+        let first = writes(&[(1, 1), (2, 1)]);
+        let second = writes(&[(3, 1), (4, 1)]);
+        assert!(checker.record_peer(2, 2, &second, None).is_ok());
+        assert!(checker.record_peer(2, 0, &first, None).is_ok());
+        assert!(checker.record_peer(2, 0, &first, None).is_ok());
+        assert!(checker.record_peer(2, 2, &second, None).is_ok());
+
+        // the same writes in another order are still reported
+        checker.record_local(&writes(&[(5, 1), (6, 1)]), None);
+        assert!(checker
+            .record_peer(2, 4, &writes(&[(6, 1), (5, 1)]), None)
+            .is_err());
+    }
+
+    #[test]
+    fn a_missing_trace_leaves_a_gap() {
+        let mut checker = OrderChecker::new();
+        checker.record_local(&writes(&[(1, 1), (2, 1), (3, 1), (4, 1)]), None);
+        // the trace of writes 0 and 1 was lost
+        assert!(checker
+            .record_peer(2, 2, &writes(&[(3, 1), (4, 1)]), None)
+            .is_ok());
+        assert!(checker.record_peer(2, 4, &writes(&[(5, 1)]), None).is_ok());
+        checker.record_local(&writes(&[(6, 1), (5, 1)]), None);
+        assert!(checker.record_peer(2, 5, &writes(&[(6, 1)]), None).is_err());
+    }
+
+    #[test]
+    fn writes_a_snapshot_covers_are_not_compared() {
+        // this replica installed a snapshot of (1, 1) and (2, 1), the peer executed them
+        let mut checker = OrderChecker::new();
+        let coverage = (HashMap::from([(1, 1)]), HashSet::from([(2, 1)]));
+        checker.record_local(&writes(&[(3, 1)]), Some(coverage));
+        let theirs = writes(&[(1, 1), (2, 1), (3, 1)]);
+        assert!(checker.record_peer(2, 0, &theirs, None).is_ok());
+
+        // and the other way around: the peer traced (1, 1) and (2, 1) before its snapshot, and
+        // again after it, which a late trace from before the snapshot does not bring back
+        let mut checker = OrderChecker::new();
+        checker.record_local(&writes(&[(1, 1), (2, 1), (3, 1)]), None);
+        let reset = (3, (HashMap::from([(1, 1)]), HashSet::new()));
+        assert!(checker
+            .record_peer(2, 3, &writes(&[(2, 1), (3, 1)]), Some(reset.clone()))
+            .is_ok());
+        assert!(checker
+            .record_peer(
+                2,
+                0,
+                &writes(&[(2, 1), (1, 1), (3, 1)]),
+                Some(reset.clone())
+            )
+            .is_ok());
+        checker.record_local(&writes(&[(4, 1), (5, 1)]), None);
+        assert!(checker
+            .record_peer(2, 5, &writes(&[(5, 1), (4, 1)]), Some(reset))
+            .is_err());
+    }
+}
@@ -0,0 +1,533 @@
+use crate::network::failure_detector::FailureDetector;
+use crate::network::order_checker::OrderChecker;
+use crate::network::replica::{
+    ClientReply, CommandState, CommittedDeps, Event, Instance, Recovery, Replica, ReplicaConfig,
+    ReplicaState,
+};
+use crate::network::state_machine::StateMachine;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// effect of an event the I/O layer carries out, in order
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Action<C> {
+    // Send(peer, message), connecting to the peer if needed
+    Send(SocketAddr, Event<C>),
+    // Reply(reply, client address)
+    Reply(ClientReply, SocketAddr),
+    // Disconnect(peer): the peer was removed from the configuration
+    Disconnect(SocketAddr),
+    // Save(file name, contents)
+    Save(String, String),
+    // Halt(reason): a check or the write-ahead log failed and the replica must stop
+    Halt(String),
+}
+
+// (time sent, PreAccept, peers not sent to)
+pub type ThriftyPreAccept<C> = (Instant, Event<C>, Vec<SocketAddr>);
+
+// EPaxos protocol of one replica without any I/O: it handles one event at a time and returns
+// the messages and effects the event produces, so that it can run over TCP, in memory or in a
+// simulation
+pub struct Protocol<S: StateMachine> {
+    id: u8,
+    addr: SocketAddr,
+    config: ReplicaConfig,
+    replica_state: Arc<Mutex<ReplicaState<S>>>,
+    detector: Arc<Mutex<FailureDetector>>,
+    // addresses of the other replicas of the configuration, broadcasts go to each of them
+    peers: Vec<SocketAddr>,
+    // thrifty PreAccepts waiting for their fast quorum
+    thrifty_pending: HashMap<Instance, ThriftyPreAccept<S::Command>>,
+    // execution order of writes of this replica and its peers, in check_order mode
+    order_checker: OrderChecker,
+    // when we last asked a peer for a snapshot, if we are waiting for one
+    snapshot_requested: Option<Instant>,
+    // addresses of the replicas removed from the configuration
+    removed: HashSet<SocketAddr>,
+}
+
+impl<S: StateMachine> Protocol<S> {
+    pub fn new(
+        id: u8,
+        addr: SocketAddr,
+        config: ReplicaConfig,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        detector: Arc<Mutex<FailureDetector>>,
+        peers: Vec<SocketAddr>,
+    ) -> Self {
+        Protocol {
+            id,
+            addr,
+            config,
+            replica_state,
+            detector,
+            peers,
+            thrifty_pending: HashMap::new(),
+            order_checker: OrderChecker::new(),
+            snapshot_requested: None,
+            removed: HashSet::new(),
+        }
+    }
+
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    fn send(actions: &mut Vec<Action<S::Command>>, peer: SocketAddr, message: Event<S::Command>) {
+        actions.push(Action::Send(peer, message));
+    }
+
+    fn multicast(
+        actions: &mut Vec<Action<S::Command>>,
+        peers: &[SocketAddr],
+        message: &Event<S::Command>,
+    ) {
+        for peer in peers {
+            Self::send(actions, *peer, message.clone());
+        }
+    }
+
+    fn broadcast(&self, actions: &mut Vec<Action<S::Command>>, message: &Event<S::Command>) {
+        Self::multicast(actions, &self.peers, message);
+    }
+
+    fn log_state(&self, n: u8) {
+        if self.config.log_state {
+            println!("{}", Replica::format_log(n, self.replica_state.clone()));
+        }
+    }
+
+    // handles event and returns what the I/O layer has to do about it, replies to the clients
+    // of the commands it executed included
+    pub fn handle(&mut self, event: Event<S::Command>) -> Vec<Action<S::Command>> {
+        let mut actions = Vec::new();
+        self.step(event, &mut actions);
+        for (reply, addr) in Replica::atomic_take_replies(self.replica_state.clone()) {
+            actions.push(Action::Reply(reply, addr));
+        }
+        // the messages and replies may rely on a change that is not in the log
+        if let Some(failure) = Replica::atomic_wal_failure(self.replica_state.clone()) {
+            return vec![Action::Halt(failure)];
+        }
+        actions
+    }
+
+    fn step(&mut self, event: Event<S::Command>, actions: &mut Vec<Action<S::Command>>) {
+        let replica_id = self.id;
+        let replica_addr = self.addr;
+        let replica_state = self.replica_state.clone();
+
+        // late message about an instance every replica has executed
+        if let Some(ins) = event.instance() {
+            if Replica::atomic_is_collected(replica_state.clone(), ins) {
+                return;
+            }
+        }
+
+        // quorum sizes follow the current configuration, replicas outside of it only learn
+        // commits
+        let (n, member) = Replica::atomic_membership(replica_id, replica_state.clone());
+        if let Some(sender) = event.sender() {
+            if self.removed.contains(&sender) {
+                return;
+            }
+        }
+        let commit = matches!(event, Event::Commit(_, _, _, _, _, _, _));
+        if !member && !commit && event.instance().is_some() {
+            return;
+        }
+
+        // Process event and construct reply.
+        match event {
+            Event::Ping(addr, _, round) => {
+                println!("Pong back to {}", addr);
+                let message = Event::Pong(replica_addr, replica_id, round);
+                Self::send(actions, addr, message);
+            }
+            Event::Pong(addr, id, round) => {
+                self.detector
+                    .lock()
+                    .unwrap()
+                    .heard_from(addr, id, Some(round));
+            }
+            Event::Heartbeat => {
+                let (suspected, round) = {
+                    let mut fd = self.detector.lock().unwrap();
+                    (fd.update(), fd.next_round())
+                };
+                for peer in suspected {
+                    println!("Suspecting {}", peer);
+                }
+
+                // add the replicas that joined the configuration to the peers, forget removed ones
+                let (joined, left) = Replica::atomic_membership_changes(replica_state.clone());
+                for id in left {
+                    let addr = self.detector.lock().unwrap().remove_replica(id);
+                    if id == replica_id {
+                        println!("Removed from the configuration");
+                    } else if let Some(addr) = addr {
+                        println!("Replica {} at {} left", id, addr);
+                        self.peers.retain(|peer| *peer != addr);
+                        self.removed.insert(addr);
+                        actions.push(Action::Disconnect(addr));
+                    }
+                }
+                for addr in joined {
+                    self.removed.remove(&addr);
+                    if addr != replica_addr && !self.peers.contains(&addr) {
+                        self.peers.push(addr);
+                    }
+                }
+
+                let message = Event::Ping(replica_addr, replica_id, round);
+                self.broadcast(actions, &message);
+            }
+            Event::SaveState => {
+                let formatted_state = Replica::format_log(n, replica_state.clone());
+                actions.push(Action::Save(
+                    format!("id_{}.txt", replica_id),
+                    formatted_state,
+                ));
+            }
+
+            // EPaxos client request handling
+            Event::ReceivedRequest(_) if !member => {
+                println!("Not a member of the configuration, ignoring request");
+            }
+            Event::ReceivedRequest(req) => {
+                let (seq, deps, ins) = Replica::atomic_request_preaccept(
+                    replica_id,
+                    replica_state.clone(),
+                    req.clone(),
+                    1,
+                    HashMap::new(),
+                    None,
+                    replica_id,
+                );
+                let ballot = Replica::atomic_ballot(replica_state.clone(), ins);
+
+                let deps: CommittedDeps = CommittedDeps { committed: deps };
+                let message =
+                    Event::PreAccept(req, seq, deps, ins, ballot, replica_addr, replica_id);
+                if self.config.thrifty {
+                    // Send PreAccept to the closest fast quorum only, the others get it if
+                    // the quorum does not reply in time
+                    let quorum = self
+                        .detector
+                        .lock()
+                        .unwrap()
+                        .closest(&self.peers, usize::from(Replica::<S>::fast_quorum(n) - 1));
+                    Self::multicast(actions, &quorum, &message);
+
+                    let rest = self
+                        .peers
+                        .iter()
+                        .filter(|p| !quorum.contains(p))
+                        .cloned()
+                        .collect();
+                    self.thrifty_pending
+                        .insert(ins, (self.config.clock.now(), message, rest));
+                } else {
+                    // Send PreAccept to all:
+                    self.broadcast(actions, &message);
+                }
+            }
+            Event::PreAccept(req, cseq, cdeps, cins, ballot, leader_addr, leader_id) => {
+                if let Err(promised) =
+                    Replica::atomic_check_ballot(replica_state.clone(), ballot, cins)
+                {
+                    let message = Event::Nack(promised, cins, replica_addr, replica_id);
+                    Self::send(actions, leader_addr, message);
+                    return;
+                }
+
+                let (seq, deps, _) = Replica::atomic_request_preaccept(
+                    replica_id,
+                    replica_state.clone(),
+                    req.clone(),
+                    cseq,
+                    cdeps.committed,
+                    Some(cins),
+                    leader_id,
+                );
+
+                let deps: CommittedDeps = CommittedDeps { committed: deps };
+                let message =
+                    Event::PreAcceptOK(req, seq, deps, cins, ballot, replica_addr, leader_id);
+                Self::send(actions, leader_addr, message);
+            }
+            Event::PreAcceptOK(req, cseq, cdeps, cins, ballot, sender, leader_id) => {
+                // reply to a ballot we no longer lead at
+                if ballot != Replica::atomic_ballot(replica_state.clone(), cins) {
+                    return;
+                }
+
+                let path = Replica::path(
+                    n,
+                    replica_state.clone(),
+                    req.clone(),
+                    cseq,
+                    cdeps.committed,
+                    cins,
+                    leader_id,
+                    sender,
+                );
+                // either take fast or slow, or not enough, late, or some error occurred (check
+                // log for error)
+                if let Some((seq, deps, take_fast)) = path {
+                    self.thrifty_pending.remove(&cins);
+                    if take_fast {
+                        self.log_state(n);
+                        Replica::execute_command(replica_state.clone(), replica_id, cins);
+
+                        // notify other replicas about the commit
+                        let message =
+                            Event::Commit(req, seq, deps, cins, ballot, replica_addr, leader_id);
+                        self.broadcast(actions, &message);
+                    } else {
+                        // notify other replicas about the accept
+                        let message =
+                            Event::Accept(req, seq, deps, cins, ballot, replica_addr, leader_id);
+                        self.broadcast(actions, &message);
+                    }
+                }
+            }
+            Event::Commit(req, cseq, cdeps, cins, ballot, _, leader_id) => {
+                // a commit is final and safe to apply whatever its ballot, only raise the
+                // promise so that the state records the ballot it was decided at
+                let _ = Replica::atomic_check_ballot(replica_state.clone(), ballot, cins);
+                Replica::atomic_update_state(
+                    replica_state.clone(),
+                    req,
+                    cseq,
+                    cdeps,
+                    cins,
+                    CommandState::Committed,
+                    leader_id,
+                );
+                self.log_state(n);
+                Replica::execute_command(replica_state.clone(), replica_id, cins);
+            }
+            Event::Accept(req, cseq, cdeps, cins, ballot, leader_addr, leader_id) => {
+                if let Err(promised) =
+                    Replica::atomic_check_ballot(replica_state.clone(), ballot, cins)
+                {
+                    let message = Event::Nack(promised, cins, replica_addr, replica_id);
+                    Self::send(actions, leader_addr, message);
+                    return;
+                }
+
+                Replica::atomic_update_state(
+                    replica_state.clone(),
+                    req.clone(),
+                    cseq,
+                    cdeps.clone(),
+                    cins,
+                    CommandState::Accepted,
+                    leader_id,
+                );
+
+                // reply to leader
+                let message =
+                    Event::AcceptOK(req, cseq, cdeps, cins, ballot, replica_addr, leader_id);
+                Self::send(actions, leader_addr, message);
+            }
+            Event::AcceptOK(req, cseq, cdeps, cins, ballot, sender, leader_id) => {
+                // reply to a ballot we no longer lead at
+                if ballot != Replica::atomic_ballot(replica_state.clone(), cins) {
+                    return;
+                }
+
+                let commit = Replica::atomic_accept(
+                    n,
+                    replica_state.clone(),
+                    req.clone(),
+                    cseq,
+                    cdeps.clone(),
+                    cins,
+                    leader_id,
+                    sender,
+                );
+                if commit {
+                    self.log_state(n);
+                    Replica::execute_command(replica_state.clone(), replica_id, cins);
+
+                    // notify other replicas about the commit
+                    let message =
+                        Event::Commit(req, cseq, cdeps, cins, ballot, replica_addr, leader_id);
+                    self.broadcast(actions, &message);
+                }
+            }
+            Event::Nack(promised, ins, sender_addr, _) => {
+                println!("{} promised {:?} for {:?}", sender_addr, promised, ins);
+                Replica::atomic_nack(replica_state.clone(), promised, ins);
+                self.thrifty_pending.remove(&ins);
+            }
+            Event::ShareTrace => {
+                let (writes, reset) = Replica::atomic_take_trace(replica_state.clone());
+                let from = self.order_checker.record_local(&writes, reset);
+
+                let reset = self.order_checker.reset();
+                let message = Event::Trace(from, writes, reset, replica_id);
+                self.broadcast(actions, &message);
+            }
+            Event::Trace(from, writes, reset, sender_id) => {
+                let checked = self
+                    .order_checker
+                    .record_peer(sender_id, from, &writes, reset);
+                if let Err(violation) = checked {
+                    actions.push(Action::Halt(format!(
+                        "Execution order check failed: replica {} {}",
+                        replica_id, violation
+                    )));
+                }
+            }
+            Event::TakeCheckpoint => {
+                let prefix = Replica::atomic_executed_prefix(replica_state.clone());
+                let removed = Replica::atomic_checkpoint(
+                    replica_id,
+                    replica_state.clone(),
+                    replica_id,
+                    prefix.clone(),
+                );
+                if removed > 0 {
+                    println!("Checkpoint: collected {} instances", removed);
+                }
+
+                let message = Event::Checkpoint(prefix, replica_addr, replica_id);
+                self.broadcast(actions, &message);
+            }
+            Event::Checkpoint(prefix, sender_addr, sender_id) => {
+                // a replica that lost its state or fell far behind catches up from a snapshot
+                // instead of from every instance, some of which may already be collected
+                let lag = Replica::atomic_lag(replica_state.clone(), &prefix);
+                let behind = lag >= self.config.snapshot_threshold
+                    || (lag > 0 && Replica::atomic_is_empty(replica_state.clone()));
+                let retry = match self.snapshot_requested {
+                    Some(t) => self.config.clock.since(t) >= self.config.recovery_timeout,
+                    None => true,
+                };
+                if behind && retry {
+                    println!(
+                        "{} instances behind {}, requesting a snapshot",
+                        lag, sender_addr
+                    );
+                    self.snapshot_requested = Some(self.config.clock.now());
+                    let message = Event::RequestSnapshot(replica_addr, replica_id);
+                    Self::send(actions, sender_addr, message);
+                }
+
+                let removed = Replica::atomic_checkpoint(
+                    replica_id,
+                    replica_state.clone(),
+                    sender_id,
+                    prefix,
+                );
+                if removed > 0 {
+                    println!("Checkpoint: collected {} instances", removed);
+                }
+            }
+            Event::RequestSnapshot(sender_addr, _) => {
+                let snapshot = Replica::atomic_snapshot(replica_state.clone());
+                let message = Event::Snapshot(snapshot, replica_addr, replica_id);
+                Self::send(actions, sender_addr, message);
+            }
+            Event::Snapshot(snapshot, sender_addr, _) => {
+                self.snapshot_requested = None;
+                let pending =
+                    Replica::atomic_install_snapshot(replica_id, replica_state.clone(), snapshot);
+                if let Some(pending) = pending {
+                    println!("Installed snapshot from {}", sender_addr);
+                    for ins in pending {
+                        Replica::execute_command(replica_state.clone(), replica_id, ins);
+                    }
+                }
+            }
+            Event::SyncLog => {
+                Replica::atomic_sync_log(replica_state.clone());
+            }
+            Event::CheckThrifty => {
+                let (clock, timeout) = (&self.config.clock, self.config.thrifty_timeout);
+                let expired: Vec<Instance> = self
+                    .thrifty_pending
+                    .iter()
+                    .filter(|(_, (sent, _, _))| clock.since(*sent) >= timeout)
+                    .map(|(ins, _)| *ins)
+                    .collect();
+                for ins in expired {
+                    if let Some((_, message, rest)) = self.thrifty_pending.remove(&ins) {
+                        println!("Fast quorum for {:?} timed out, sending to all", ins);
+                        Self::multicast(actions, &rest, &message);
+                    }
+                }
+            }
+
+            // EPaxos explicit prepare (recovery)
+            // a replica outside of the configuration does not take part in recovery, its
+            // Prepares would count towards the quorums of a configuration it is not in
+            Event::CheckTimeouts if !member => (),
+            Event::CheckTimeouts => {
+                let prepares = Replica::atomic_recovery_candidates(
+                    replica_id,
+                    replica_state.clone(),
+                    self.detector.clone(),
+                    self.config.recovery_timeout,
+                );
+                for (ins, ballot) in prepares {
+                    println!("Recovering {:?} with ballot {:?}", ins, ballot);
+                    let message = Event::Prepare(ballot, ins, replica_addr, replica_id);
+                    self.broadcast(actions, &message);
+                }
+            }
+            Event::Prepare(ballot, ins, sender_addr, _) => {
+                let message = match Replica::atomic_prepare(replica_state.clone(), ballot, ins) {
+                    Ok((info, accepted, as_proposed)) => Event::PrepareOK(
+                        ballot,
+                        ins,
+                        info,
+                        accepted,
+                        as_proposed,
+                        replica_addr,
+                        replica_id,
+                    ),
+                    Err(promised) => Event::Nack(promised, ins, replica_addr, replica_id),
+                };
+                Self::send(actions, sender_addr, message);
+            }
+            Event::PrepareOK(ballot, ins, info, accepted, as_proposed, _, sender_id) => {
+                let recovery = Replica::atomic_prepare_ok(
+                    n,
+                    replica_id,
+                    replica_state.clone(),
+                    ballot,
+                    ins,
+                    (info, accepted, as_proposed),
+                    sender_id,
+                );
+                let message = match recovery {
+                    Some(Recovery::Commit(req, seq, deps)) => {
+                        Replica::execute_command(replica_state.clone(), replica_id, ins);
+                        Event::Commit(req, seq, deps, ins, ballot, replica_addr, replica_id)
+                    }
+                    Some(Recovery::Accept(req, seq, deps)) => {
+                        Event::Accept(req, seq, deps, ins, ballot, replica_addr, replica_id)
+                    }
+                    Some(Recovery::PreAccept(req, seq, deps)) => {
+                        let deps = CommittedDeps { committed: deps };
+                        Event::PreAccept(req, seq, deps, ins, ballot, replica_addr, replica_id)
+                    }
+                    // not enough replies yet, or a late reply
+                    None => return,
+                };
+                self.broadcast(actions, &message);
+            }
+            // Message, Forward and Acknowledge test the network setup, they are not part of the
+            // protocol
+            _ => (),
+        };
+    }
+}
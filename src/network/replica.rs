@@ -1,18 +1,27 @@
+use crate::network::clock::Clock;
+use crate::network::failure_detector::FailureDetector;
+use crate::network::nemesis::{Nemesis, NemesisConfig};
+use crate::network::order_checker::{Coverage, TraceReset};
+use crate::network::protocol::{Action, Protocol};
+use crate::network::state_machine::{Access, Interference, StateMachine};
+use crate::network::transport::Transport;
+use crate::network::wal::{FsyncPolicy, Wal};
 use async_channel::{unbounded, Receiver, Sender};
-use petgraph::algo::kosaraju_scc;
-use petgraph::graph::DiGraph;
+use petgraph::stable_graph::{NodeIndex, StableDiGraph};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use smol::io::{self, AsyncBufReadExt, AsyncWriteExt};
-use smol::stream::StreamExt;
-use smol::Async;
+use smol::io;
+use smol::Timer;
 use std::cmp::max;
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
 use std::io::Write;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::SocketAddr;
+use std::process::exit;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
@@ -21,23 +30,107 @@ pub struct CommittedDeps {
     pub committed: HashMap<Instance, bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ClientRequest {
-    // last one is request ID
-    Read(String, SocketAddr, u64),
-    Write(String, String, SocketAddr, u64),
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientRequest<C> {
+    // Command(command of the replicated state machine, client address, request ID)
+    Command(C, SocketAddr, RequestId),
+    // committed by recovery when no replica knows the original command
+    NoOp,
+    // reconfiguration, interferes with every command:
+    // AddReplica(address of the new replica, client address, request ID), replies the new ID
+    AddReplica(SocketAddr, SocketAddr, RequestId),
+    // RemoveReplica(replica ID, client address, request ID)
+    RemoveReplica(u8, SocketAddr, RequestId),
+}
+
+impl<C: Interference> ClientRequest<C> {
+    // keys read or written by the request
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            ClientRequest::Command(cmd, _, _) => cmd.keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn access(&self) -> Access {
+        match self {
+            ClientRequest::Command(cmd, _, _) => cmd.access(),
+            _ => Access::Write,
+        }
+    }
+
+    // address to reply to and ID of the request, if it comes from a client
+    pub fn client(&self) -> Option<(SocketAddr, RequestId)> {
+        match self {
+            ClientRequest::Command(_, addr, id) => Some((*addr, *id)),
+            ClientRequest::AddReplica(_, addr, id) => Some((*addr, *id)),
+            ClientRequest::RemoveReplica(_, addr, id) => Some((*addr, *id)),
+            ClientRequest::NoOp => None,
+        }
+    }
+
+    pub fn is_reconfiguration(&self) -> bool {
+        matches!(
+            self,
+            ClientRequest::AddReplica(_, _, _) | ClientRequest::RemoveReplica(_, _, _)
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientReply {
-    // Reply(result, ID)
+    // Reply(result, request number)
     Reply(Option<String>, u64),
 }
 
+// client ID, request number, lowest request number the client has not received the reply of
+pub type RequestId = (u64, u64, u64);
+
+// replicated state of a client: (lowest request number the client has not received the reply
+// of, replies of the requests executed from there on)
+pub type ClientSession = (u64, HashMap<u64, Option<String>>);
+
 pub type Instance = (u8, u64); // ID of replica, instance number
 
 pub type SeqNumber = u64;
 
+pub type Ballot = (u64, u64, u8); // epoch, ballot number, ID of replica that owns the ballot
+
+// (cmd, seq, deps, state) of an instance as reported in a PrepareOK
+pub type InstanceInfo<C> = (ClientRequest<C>, SeqNumber, HashSet<Instance>, CommandState);
+
+// (info, ballot the info was accepted at, whether it was PreAccepted with the attributes its
+// leader proposed)
+pub type AcceptedInfo<C> = (Option<InstanceInfo<C>>, Ballot, bool);
+
+// (info, accepted ballot, PreAccepted as proposed, sender ID)
+pub type PrepareReply<C> = (Option<InstanceInfo<C>>, Ballot, bool, u8);
+
+// (senders, (seq, deps) of every PreAcceptOK received so far)
+pub type PreAcceptReplies = (
+    HashSet<SocketAddr>,
+    Vec<(SeqNumber, HashMap<Instance, bool>)>,
+);
+
+// (ballot of the recovery, PrepareOKs received so far)
+pub type PrepareReplies<C> = (Ballot, Vec<PrepareReply<C>>);
+
+// (cmd, seq, deps, state, leader, ballot the state was accepted at)
+pub type InstanceEntry<C> = (
+    ClientRequest<C>,
+    SeqNumber,
+    HashSet<Instance>,
+    CommandState,
+    u8,
+    Ballot,
+);
+
+// (instance, cmd, seq, deps, leader) of a committed instance
+pub type CommittedInstance<C> = (Instance, ClientRequest<C>, SeqNumber, HashSet<Instance>, u8);
+
+// (executed prefix of every replica, state machine)
+pub type SettledState = (HashMap<u8, u64>, String);
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum CommandState {
     PreAccepted,
@@ -55,144 +148,536 @@ impl fmt::Display for CommandState {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ReplicaState {
+#[derive(Debug)]
+pub struct ReplicaState<S: StateMachine> {
     instance_number: u64,
-    cmds: HashMap<
-        Instance,
-        (
-            ClientRequest,
-            SeqNumber,
-            HashSet<Instance>,
-            CommandState,
-            u8,
-        ),
-    >, // (cmd, seq, deps, state, leader)
-    dict: HashMap<String, String>,
-    preaccept_replies: HashMap<Instance, Vec<(SeqNumber, HashMap<Instance, bool>)>>,
-    naccept: HashMap<Instance, u8>,
-    dep_graph: DiGraph<Instance, ()>,
+    cmds: HashMap<Instance, InstanceEntry<S::Command>>,
+    // replicated state the executed commands were applied to
+    state: S,
+    // a duplicated reply counts once towards a quorum
+    preaccept_replies: HashMap<Instance, PreAcceptReplies>,
+    // replicas that sent an AcceptOK for each instance
+    naccept: HashMap<Instance, HashSet<SocketAddr>>,
+    dep_graph: StableDiGraph<Instance, ()>,
+    // node of every instance in dep_graph
+    node_index: HashMap<Instance, NodeIndex>,
+    // uncommitted instance -> committed instances whose execution waits for it
+    waiting: HashMap<Instance, HashSet<Instance>>,
     executed: HashSet<Instance>,
+    // conflict index: key -> replica -> highest instance number of that replica on the key, of
+    // any request, of requests that are not reads and of requests that are not commutative
+    latest: HashMap<String, HashMap<u8, u64>>,
+    latest_write: HashMap<String, HashMap<u8, u64>>,
+    latest_non_commutative: HashMap<String, HashMap<u8, u64>>,
+    // whether writes are traced, in check_order mode
+    record_trace: bool,
+    // writes in the order they were executed since the trace was last shared, with their key
+    trace: Vec<(Instance, String)>,
+    // snapshot installed since the trace was last shared, the trace starts after it
+    trace_reset: Option<Coverage>,
+    // ballots: --------------------------------------------------------------
+    // epoch of the current configuration, first component of every new ballot
+    epoch: u64,
+    // highest ballot promised for each instance, (0, 0, leader) if absent
+    ballots: HashMap<Instance, Ballot>,
+    // recovery: -------------------------------------------------------------
+    // instances this replica is recovering, with the PrepareOKs received so far
+    recovering: HashMap<Instance, PrepareReplies<S::Command>>,
+    // instances re-proposed by recovery that must not commit on the fast path
+    avoid_fast_path: HashSet<Instance>,
+    // instances last PreAccepted here with the attributes their leader proposed, only these may
+    // be part of a fast quorum
+    as_proposed: HashSet<Instance>,
+    // instances led by this replica that committed on the fast path / went through Accept
+    fast_commits: u64,
+    slow_commits: u64,
+    // last time an uncommitted (or unknown) instance made progress
+    last_progress: HashMap<Instance, Instant>,
+    // highest instance number seen from every replica
+    highest_seen: HashMap<u8, u64>,
+    // membership: -----------------------------------------------------------
+    // IDs of the replicas of the current configuration
+    members: HashSet<u8>,
+    // ID of the next replica to be added
+    next_id: u8,
+    // replica -> highest instance number of that replica holding a reconfiguration
+    latest_reconfig: HashMap<u8, u64>,
+    // addresses of added replicas and IDs of removed replicas the dispatcher has not
+    // connected to or disconnected from yet
+    joined: Vec<SocketAddr>,
+    left: Vec<u8>,
+    // replies to the clients of the executed commands the dispatcher has not sent yet
+    replies: Vec<(ClientReply, SocketAddr)>,
+    // clients: --------------------------------------------------------------
+    // client ID -> session, to answer retried requests without executing them again
+    clients: HashMap<u64, ClientSession>,
+    // client ID -> replica -> highest instance number of that replica holding a request of the
+    // client, the requests of a client interfere so that every replica updates its session in
+    // the same order
+    latest_client: HashMap<u64, HashMap<u8, u64>>,
+    // checkpoints: ----------------------------------------------------------
+    // replica -> every instance of that replica up to this number has been executed here
+    executed_prefix: HashMap<u8, u64>,
+    // executed prefixes last received from every replica, this one included
+    peer_prefixes: HashMap<u8, HashMap<u8, u64>>,
+    // replica -> instances up to this number were executed by every replica and removed from
+    // the state, they are tombstones and messages about them are ignored
+    collected: HashMap<u8, u64>,
+    // write-ahead log of the state changes, if the replica is durable
+    wal: Option<Wal>,
+    // first error of the write-ahead log, the replica must not act on changes it could not log
+    wal_failure: Option<String>,
+    clock: Clock,
 }
 
+// records of the write-ahead log, replayed in order when the replica restarts
 #[derive(Serialize, Deserialize, Debug)]
-pub enum Event {
+pub enum LogRecord<C> {
+    // State(instance, cmd, seq, deps, state, leader, ballot the state was accepted at)
+    State(
+        Instance,
+        ClientRequest<C>,
+        SeqNumber,
+        HashSet<Instance>,
+        CommandState,
+        u8,
+        Ballot,
+    ),
+    // Promise(instance, ballot promised)
+    Promise(Instance, Ballot),
+    // AsProposed(instance): the last State of the instance kept the attributes of its leader
+    AsProposed(Instance),
+    Executed(Instance),
+    // Collected(replica ID, instances of the replica up to this number were collected)
+    Collected(u8, u64),
+    Snapshot(Snapshot<C>),
+}
+
+// executed state of a replica, sent to a replica that fell too far behind
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot<C> {
+    // snapshot of the state machine
+    pub state: String,
+    // replica -> every instance of that replica up to this number is reflected in state
+    pub prefix: HashMap<u8, u64>,
+    // instances above the prefix that are reflected in state
+    pub executed: HashSet<Instance>,
+    // committed instances above the prefix
+    pub committed: Vec<CommittedInstance<C>>,
+    // configuration reflected in state: (members, next ID, epoch)
+    pub configuration: (HashSet<u8>, u8, u64),
+    pub clients: HashMap<u64, ClientSession>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event<C> {
     Message(SocketAddr, String),
-    Ping(SocketAddr, String),
-    Pong(SocketAddr, String),
+    // Ping/Pong(sender, sender ID, heartbeat round)
+    Ping(SocketAddr, u8, u64),
+    Pong(SocketAddr, u8, u64),
     Forward(SocketAddr, String),
     Acknowledge(SocketAddr),
     SaveState,
     // EPaxos messages: --------------------------------------------------------
-    ReceivedRequest(ClientRequest),
-    // message(gamma, seq, deps, instance, sender)
-    PreAccept(ClientRequest, u64, CommittedDeps, Instance, SocketAddr, u8),
-    PreAcceptOK(ClientRequest, u64, CommittedDeps, Instance, SocketAddr, u8),
+    ReceivedRequest(ClientRequest<C>),
+    // message(gamma, seq, deps, instance, ballot, sender, leader ID)
+    PreAccept(
+        ClientRequest<C>,
+        u64,
+        CommittedDeps,
+        Instance,
+        Ballot,
+        SocketAddr,
+        u8,
+    ),
+    PreAcceptOK(
+        ClientRequest<C>,
+        u64,
+        CommittedDeps,
+        Instance,
+        Ballot,
+        SocketAddr,
+        u8,
+    ),
     Accept(
-        ClientRequest,
+        ClientRequest<C>,
         u64,
         HashSet<Instance>,
         Instance,
+        Ballot,
         SocketAddr,
         u8,
     ),
     AcceptOK(
-        ClientRequest,
+        ClientRequest<C>,
         u64,
         HashSet<Instance>,
         Instance,
+        Ballot,
         SocketAddr,
         u8,
     ),
     Commit(
-        ClientRequest,
+        ClientRequest<C>,
         u64,
         HashSet<Instance>,
         Instance,
+        Ballot,
+        SocketAddr,
+        u8,
+    ),
+    // Prepare(ballot, instance, sender, sender ID)
+    Prepare(Ballot, Instance, SocketAddr, u8),
+    // PrepareOK(ballot, instance, info, accepted ballot, PreAccepted as proposed, sender,
+    // sender ID)
+    PrepareOK(
+        Ballot,
+        Instance,
+        Option<InstanceInfo<C>>,
+        Ballot,
+        bool,
         SocketAddr,
         u8,
     ),
+    // Nack(promised ballot, instance, sender, sender ID): reply to a message with a stale ballot
+    Nack(Ballot, Instance, SocketAddr, u8),
+    // local timer: look for stalled instances that need recovery
+    CheckTimeouts,
+    // local timer: ping every peer and update the failure detector
+    Heartbeat,
+    // local timer: send thrifty PreAccepts that did not get a fast quorum in time to everyone
+    CheckThrifty,
+    // local timer: send the writes executed since the last trace to every peer
+    ShareTrace,
+    // Trace(number of the first write, writes in execution order as (instance, key), last
+    // snapshot the sender installed, sender ID)
+    Trace(u64, Vec<(Instance, String)>, Option<TraceReset>, u8),
+    // local timer: send the executed prefix to every peer and collect what everyone executed
+    TakeCheckpoint,
+    // Checkpoint(executed prefix of every replica's instances, sender, sender ID)
+    Checkpoint(HashMap<u8, u64>, SocketAddr, u8),
+    // RequestSnapshot(sender, sender ID): ask a replica that is ahead for its executed state
+    RequestSnapshot(SocketAddr, u8),
+    // Snapshot(snapshot, sender, sender ID)
+    Snapshot(Snapshot<C>, SocketAddr, u8),
+    // local timer: fsync the write-ahead log, with the grouped fsync policy
+    SyncLog,
+}
+
+impl<C> Event<C> {
+    // instance an EPaxos message is about
+    pub fn instance(&self) -> Option<Instance> {
+        match self {
+            Event::PreAccept(_, _, _, ins, _, _, _)
+            | Event::PreAcceptOK(_, _, _, ins, _, _, _)
+            | Event::Accept(_, _, _, ins, _, _, _)
+            | Event::AcceptOK(_, _, _, ins, _, _, _)
+            | Event::Commit(_, _, _, ins, _, _, _)
+            | Event::Prepare(_, ins, _, _)
+            | Event::PrepareOK(_, ins, _, _, _, _, _)
+            | Event::Nack(_, ins, _, _) => Some(*ins),
+            _ => None,
+        }
+    }
+
+    // replica that sent an EPaxos message
+    pub fn sender(&self) -> Option<SocketAddr> {
+        match self {
+            Event::PreAccept(_, _, _, _, _, addr, _)
+            | Event::PreAcceptOK(_, _, _, _, _, addr, _)
+            | Event::Accept(_, _, _, _, _, addr, _)
+            | Event::AcceptOK(_, _, _, _, _, addr, _)
+            | Event::Commit(_, _, _, _, _, addr, _)
+            | Event::Prepare(_, _, addr, _)
+            | Event::PrepareOK(_, _, _, _, _, addr, _)
+            | Event::Nack(_, _, addr, _) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // replica that sent a message, for every message that names it
+    pub fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            Event::Ping(addr, _, _)
+            | Event::Pong(addr, _, _)
+            | Event::Checkpoint(_, addr, _)
+            | Event::RequestSnapshot(addr, _)
+            | Event::Snapshot(_, addr, _) => Some(*addr),
+            _ => self.sender(),
+        }
+    }
+
+    // the event is a message from another replica, not from a client or a local timer
+    pub fn from_replica(&self) -> bool {
+        matches!(
+            self,
+            Event::Ping(..)
+                | Event::Pong(..)
+                | Event::PreAccept(..)
+                | Event::PreAcceptOK(..)
+                | Event::Accept(..)
+                | Event::AcceptOK(..)
+                | Event::Commit(..)
+                | Event::Prepare(..)
+                | Event::PrepareOK(..)
+                | Event::Nack(..)
+                | Event::Trace(..)
+                | Event::Checkpoint(..)
+                | Event::RequestSnapshot(..)
+                | Event::Snapshot(..)
+        )
+    }
+
+    // name of the type of the event
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Message(..) => "Message",
+            Event::Ping(..) => "Ping",
+            Event::Pong(..) => "Pong",
+            Event::Forward(..) => "Forward",
+            Event::Acknowledge(..) => "Acknowledge",
+            Event::SaveState => "SaveState",
+            Event::ReceivedRequest(..) => "ReceivedRequest",
+            Event::PreAccept(..) => "PreAccept",
+            Event::PreAcceptOK(..) => "PreAcceptOK",
+            Event::Accept(..) => "Accept",
+            Event::AcceptOK(..) => "AcceptOK",
+            Event::Commit(..) => "Commit",
+            Event::Prepare(..) => "Prepare",
+            Event::PrepareOK(..) => "PrepareOK",
+            Event::Nack(..) => "Nack",
+            Event::CheckTimeouts => "CheckTimeouts",
+            Event::Heartbeat => "Heartbeat",
+            Event::CheckThrifty => "CheckThrifty",
+            Event::ShareTrace => "ShareTrace",
+            Event::Trace(..) => "Trace",
+            Event::TakeCheckpoint => "TakeCheckpoint",
+            Event::Checkpoint(..) => "Checkpoint",
+            Event::RequestSnapshot(..) => "RequestSnapshot",
+            Event::Snapshot(..) => "Snapshot",
+            Event::SyncLog => "SyncLog",
+        }
+    }
+}
+
+// next step of a recovery once a majority of PrepareOKs has been received
+#[derive(Debug, Clone)]
+pub enum Recovery<C> {
+    Commit(ClientRequest<C>, SeqNumber, HashSet<Instance>),
+    Accept(ClientRequest<C>, SeqNumber, HashSet<Instance>),
+    PreAccept(ClientRequest<C>, SeqNumber, HashMap<Instance, bool>),
+}
+
+// timeouts and protocol options of a replica
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    // time without progress before another leader's instance is recovered
+    pub recovery_timeout: Duration,
+    // time between two heartbeats to every peer
+    pub heartbeat_interval: Duration,
+    // time without a Pong before a peer is suspected
+    pub suspect_timeout: Duration,
+    // send PreAccept only to the closest fast quorum instead of every replica
+    pub thrifty: bool,
+    // time to wait for the thrifty fast quorum before sending PreAccept to the other replicas
+    pub thrifty_timeout: Duration,
+    // exchange execution traces and panic if two replicas wrote a key in different orders
+    pub check_order: bool,
+    // time between two checkpoints of the executed instances
+    pub checkpoint_interval: Duration,
+    // file of the write-ahead log, the state is only kept in memory if none
+    pub wal: Option<String>,
+    // when the write-ahead log is forced to disk
+    pub fsync: FsyncPolicy,
+    // time between two fsyncs of the write-ahead log with the grouped policy
+    pub fsync_interval: Duration,
+    // number of instances a peer must have executed ahead of this replica to request a snapshot
+    pub snapshot_threshold: u64,
+    // the replica joins a running cluster and waits for the configuration from a snapshot
+    pub join: bool,
+    // print the command log after every commit
+    pub log_state: bool,
+    // source of the time of every timeout, virtual in a simulation
+    pub clock: Clock,
+    // faults injected into the messages received from the other replicas
+    pub nemesis: NemesisConfig,
 }
 
-pub struct Replica {
-    replica_state: Arc<Mutex<ReplicaState>>,
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        ReplicaConfig {
+            recovery_timeout: Duration::from_millis(2000),
+            heartbeat_interval: Duration::from_millis(200),
+            suspect_timeout: Duration::from_millis(1000),
+            thrifty: false,
+            thrifty_timeout: Duration::from_millis(500),
+            check_order: false,
+            checkpoint_interval: Duration::from_millis(1000),
+            wal: None,
+            fsync: FsyncPolicy::Every,
+            fsync_interval: Duration::from_millis(10),
+            snapshot_threshold: 1000,
+            join: false,
+            log_state: true,
+            clock: Clock::Real,
+            nemesis: NemesisConfig::default(),
+        }
+    }
+}
+
+pub struct Replica<S: StateMachine> {
+    replica_state: Arc<Mutex<ReplicaState<S>>>,
+    detector: Arc<Mutex<FailureDetector>>,
     id: u8,
     addr: SocketAddr,
     listen_addr: SocketAddr,
     connections: Vec<SocketAddr>,
-    n: u8,
+    config: ReplicaConfig,
 }
 
-impl Replica {
-    pub fn new(id: u8, addr: SocketAddr, listen_addr: SocketAddr, connections: Vec<SocketAddr>, n: u8) -> Self {
+impl<S: StateMachine> Replica<S> {
+    pub fn new(
+        id: u8,
+        addr: SocketAddr,
+        listen_addr: SocketAddr,
+        connections: Vec<SocketAddr>,
+        n: u8,
+        config: ReplicaConfig,
+    ) -> Self {
         return Replica {
             id,
             addr,
             listen_addr,
+            detector: Arc::new(Mutex::new(FailureDetector::new(
+                &connections,
+                config.suspect_timeout,
+                config.clock.clone(),
+            ))),
             connections,
             replica_state: Arc::new(Mutex::new(ReplicaState {
                 instance_number: 0,
                 cmds: HashMap::new(),
-                dep_graph: DiGraph::new(),
-                dict: HashMap::new(),
+                dep_graph: StableDiGraph::new(),
+                node_index: HashMap::new(),
+                waiting: HashMap::new(),
+                state: S::default(),
                 preaccept_replies: HashMap::new(),
                 naccept: HashMap::new(),
                 executed: HashSet::new(),
+                latest: HashMap::new(),
+                latest_write: HashMap::new(),
+                latest_non_commutative: HashMap::new(),
+                record_trace: config.check_order,
+                trace: Vec::new(),
+                trace_reset: None,
+                epoch: 0,
+                ballots: HashMap::new(),
+                recovering: HashMap::new(),
+                avoid_fast_path: HashSet::new(),
+                as_proposed: HashSet::new(),
+                fast_commits: 0,
+                slow_commits: 0,
+                last_progress: HashMap::new(),
+                highest_seen: HashMap::new(),
+                // a joining replica learns the configuration with its first snapshot
+                members: match config.join {
+                    true => HashSet::new(),
+                    false => (1..=n).collect(),
+                },
+                next_id: n + 1,
+                latest_reconfig: HashMap::new(),
+                joined: Vec::new(),
+                left: Vec::new(),
+                replies: Vec::new(),
+                clients: HashMap::new(),
+                latest_client: HashMap::new(),
+                executed_prefix: HashMap::new(),
+                peer_prefixes: HashMap::new(),
+                collected: HashMap::new(),
+                wal: None,
+                wal_failure: None,
+                clock: config.clock.clone(),
             })),
-            n,
+            config,
         };
     }
 
-    pub fn format_log(n: u8, replica_state: Arc<Mutex<ReplicaState>>) -> String {
-        let rs = replica_state.lock().unwrap();
+    // liveness view of the failure detector, shared with the dispatcher
+    pub fn liveness(&self) -> Arc<Mutex<FailureDetector>> {
+        self.detector.clone()
+    }
 
-        fn key_to_string(key: String) -> String {
-            let ans: String = if key.len() < 4 {
-                let mut formatted = key.clone();
-                formatted.push_str("_".repeat(4 - key.len()).as_str());
-                formatted
-            } else {
-                key.chars().take(4).collect()
-            };
-            return ans;
-        }
+    pub fn replica_state(&self) -> Arc<Mutex<ReplicaState<S>>> {
+        self.replica_state.clone()
+    }
+
+    // protocol core of the replica, driven by start over TCP or by a simulation
+    pub fn protocol(&self) -> Protocol<S> {
+        Protocol::new(
+            self.id,
+            self.addr,
+            self.config.clone(),
+            self.replica_state.clone(),
+            self.detector.clone(),
+            self.connections.clone(),
+        )
+    }
+
+    pub fn is_suspected(&self, peer: SocketAddr) -> bool {
+        self.detector.lock().unwrap().is_suspected(peer)
+    }
+
+    pub fn suspected_peers(&self) -> Vec<SocketAddr> {
+        self.detector.lock().unwrap().suspected()
+    }
+
+    pub fn alive_peers(&self) -> Vec<SocketAddr> {
+        self.detector.lock().unwrap().alive()
+    }
+
+    pub fn format_log(n: u8, replica_state: Arc<Mutex<ReplicaState<S>>>) -> String {
+        let rs = replica_state.lock().unwrap();
 
         let max_num = match rs.cmds.keys().map(|x| -> u64 { x.1 }).max() {
             Some(x) => x,
             None => 0,
         };
 
-        let mut log =
+        // one row per replica ID, even if some ID is larger than the number of replicas
+        let rows = rs.cmds.keys().map(|x| x.0).max().unwrap_or(0).max(n);
+
+        // collected instances are not shown
+        let first = (1..=rows)
+            .map(|id| rs.collected.get(&id).cloned().unwrap_or(0))
+            .min()
+            .unwrap_or(0);
+
+        let mut log = vec![
             vec![
-                vec!["Empty[----- ........................ -----]".to_string(); max_num as usize];
-                n.into()
+                "Empty[----- ........................ -----]".to_string();
+                (max_num - first.min(max_num)) as usize
             ];
-        for ((id, num), (req, seq, _, status, leader_id)) in rs.cmds.clone().into_iter() {
+            rows.into()
+        ];
+        for ((id, num), (req, seq, _, status, leader_id, _)) in rs.cmds.clone().into_iter() {
             match req {
-                ClientRequest::Read(key, _, _) => {
-                    log[(id - 1) as usize][(num - 1) as usize] = format!(
-                        "{}{}{}{:<3}{}{}{:<2}{}",
-                        "Read-[".to_string(),
-                        key_to_string(key),
-                        ", Seq: ".to_string(),
-                        seq,
-                        " Status: ".to_string(),
-                        status,
-                        leader_id,
-                        "]".to_string(),
+                // the state machine decides how its commands are shown
+                ClientRequest::Command(cmd, _, _) => {
+                    log[(id - 1) as usize][(num - first - 1) as usize] = format!(
+                        "{}, Seq: {:<3} Status: {}{:<2}]",
+                        cmd, seq, status, leader_id
+                    );
+                }
+                ClientRequest::NoOp => {
+                    log[(id - 1) as usize][(num - first - 1) as usize] = format!(
+                        "NoOp-[----, Seq: {:<3} Status: {}{:<2}]",
+                        seq, status, leader_id
                     );
                 }
-                ClientRequest::Write(key, _, _, _) => {
-                    log[(id - 1) as usize][(num - 1) as usize] = format!(
-                        "{}{}{}{:<3}{}{}{:<2}{}",
-                        "Write[".to_string(),
-                        key_to_string(key),
-                        ", Seq: ".to_string(),
-                        seq,
-                        " Status: ".to_string(),
-                        status,
-                        leader_id,
-                        "]".to_string(),
+                ClientRequest::AddReplica(_, _, _) | ClientRequest::RemoveReplica(_, _, _) => {
+                    log[(id - 1) as usize][(num - first - 1) as usize] = format!(
+                        "Conf-[----, Seq: {:<3} Status: {}{:<2}]",
+                        seq, status, leader_id
                     );
                 }
             }
@@ -214,11 +699,25 @@ impl Replica {
         // ans.push_str("Dependency graph:\n");
         // ans.push_str(format!("{:?}\n", rs.dep_graph).as_str());
         // ans.push_str("\n");
-        ans.push_str("Dict Status:\n");
-        let mut sorted_dict: Vec<(String, String)> = rs.dict.clone().into_iter().collect();
-        sorted_dict.sort();
-        ans.push_str(format!("{:?}\n", sorted_dict).as_str());
+        ans.push_str("State:\n");
+        ans.push_str(format!("{}\n", rs.state).as_str());
         ans.push_str("\n");
+        ans.push_str("Members:\n");
+        let mut sorted_members: Vec<u8> = rs.members.clone().into_iter().collect();
+        sorted_members.sort();
+        ans.push_str(format!("{:?} epoch {}\n\n", sorted_members, rs.epoch).as_str());
+        ans.push_str("Commit paths:\n");
+        ans.push_str(
+            format!(
+                "fast {} slow {} of the instances led by this replica\n\n",
+                rs.fast_commits, rs.slow_commits
+            )
+            .as_str(),
+        );
+        ans.push_str("Collected instances:\n");
+        let mut sorted_collected: Vec<(u8, u64)> = rs.collected.clone().into_iter().collect();
+        sorted_collected.sort();
+        ans.push_str(format!("{:?}\n\n", sorted_collected).as_str());
         ans.push_str("Executed command instances:\n");
         let mut sorted_exe: Vec<Instance> = rs.executed.clone().into_iter().collect();
         sorted_exe.sort();
@@ -228,194 +727,616 @@ impl Replica {
         return ans;
     }
 
-    // returns true if requests interfere, otherwise false
-    pub fn interfere(a: ClientRequest, b: ClientRequest) -> bool {
-        match (a, b) {
-            (ClientRequest::Write(k1, _, _, _), ClientRequest::Read(k2, _, _)) => {
-                if k1 == k2 {
-                    return true;
+    // node of ins in dep_graph, added if ins has not been seen yet
+    pub fn node(rs: &mut ReplicaState<S>, ins: Instance) -> NodeIndex {
+        match rs.node_index.get(&ins) {
+            Some(node) => *node,
+            None => {
+                let node = rs.dep_graph.add_node(ins);
+                rs.node_index.insert(ins, node);
+                node
+            }
+        }
+    }
+
+    pub fn add_dependency(rs: &mut ReplicaState<S>, src: Instance, dst: Instance) {
+        // get the node indices, adding the nodes if they do not exist
+        let snode = Self::node(rs, src);
+        let dnode = Self::node(rs, dst);
+
+        // Add the edge
+        rs.dep_graph.update_edge(snode, dnode, ());
+    }
+
+    pub async fn reply_to_client<T: Transport>(
+        transport: T,
+        message: ClientReply,
+        addr: SocketAddr,
+    ) {
+        match transport.connect(addr).await {
+            Ok(mut connection) => {
+                // Intro messages.
+                println!("Replying to client: {}", addr);
+                let _ = transport.send(&mut connection, &message).await;
+            }
+            Err(_) => {
+                println!("Connection to client address {} failed", addr);
+            }
+        }
+    }
+
+    // executes cins, once committed, and every committed instance that was waiting for it
+    pub fn execute_command(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        replica_id: u8,
+        cins: Instance,
+    ) {
+        let mut rs = replica_state.lock().unwrap();
+
+        let mut roots = vec![cins];
+        if let Some(waiting) = rs.waiting.remove(&cins) {
+            roots.extend(waiting);
+        }
+        for root in roots {
+            if let Err(blocker) = Self::execute_from(&mut rs, replica_id, root) {
+                // try again when the uncommitted dependency commits
+                rs.waiting.entry(blocker).or_default().insert(root);
+            }
+        }
+
+        drop(rs);
+    }
+
+    // Tarjan's algorithm from root over the instances that have not been executed yet: every
+    // strongly connected component is executed as soon as it is complete, since by then every
+    // component it depends on has been executed; stops at the first uncommitted instance
+    fn execute_from(
+        rs: &mut ReplicaState<S>,
+        replica_id: u8,
+        root: Instance,
+    ) -> Result<(), Instance> {
+        if Self::is_executed(rs, root) {
+            return Ok(());
+        }
+
+        let mut next_index = 0;
+        let mut index: HashMap<Instance, usize> = HashMap::new();
+        let mut lowlink: HashMap<Instance, usize> = HashMap::new();
+        let mut stack: Vec<Instance> = Vec::new();
+        let mut on_stack: HashSet<Instance> = HashSet::new();
+        // depth-first search: (instance, its dependencies, next dependency to visit)
+        let mut calls: Vec<(Instance, Vec<Instance>, usize)> = Vec::new();
+
+        let mut visit = Some(root);
+        loop {
+            if let Some(ins) = visit.take() {
+                match rs.cmds.get(&ins) {
+                    Some(cmd) if cmd.3 == CommandState::Committed => (),
+                    _ => return Err(ins),
                 }
-                return false;
+                index.insert(ins, next_index);
+                lowlink.insert(ins, next_index);
+                next_index += 1;
+                stack.push(ins);
+                on_stack.insert(ins);
+                let node = Self::node(rs, ins);
+                let deps = rs
+                    .dep_graph
+                    .neighbors(node)
+                    .map(|d| rs.dep_graph[d])
+                    .collect();
+                calls.push((ins, deps, 0));
             }
-            (ClientRequest::Read(k1, _, _), ClientRequest::Write(k2, _, _, _)) => {
-                if k1 == k2 {
-                    return true;
+
+            let (ins, deps, next) = match calls.last_mut() {
+                Some(call) => call,
+                None => break,
+            };
+            let ins = *ins;
+
+            if *next < deps.len() {
+                let d = deps[*next];
+                *next += 1;
+                if Self::is_executed(rs, d) {
+                    continue;
                 }
-                return false;
+                if !index.contains_key(&d) {
+                    visit = Some(d);
+                } else if on_stack.contains(&d) {
+                    let low = lowlink[&ins].min(index[&d]);
+                    lowlink.insert(ins, low);
+                }
+                continue;
+            }
+
+            // every dependency of ins has been visited
+            calls.pop();
+            if let Some((parent, _, _)) = calls.last() {
+                let low = lowlink[parent].min(lowlink[&ins]);
+                lowlink.insert(*parent, low);
             }
-            (ClientRequest::Write(k1, _, _, _), ClientRequest::Write(k2, _, _, _)) => {
-                if k1 == k2 {
-                    return true;
+            if lowlink[&ins] == index[&ins] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack.remove(&member);
+                    component.push(member);
+                    if member == ins {
+                        break;
+                    }
                 }
-                return false;
+
+                // sort each connected comp by seq number, ties broken by instance so that every
+                // replica executes the component in the same order
+                component.sort_by_key(|member| (rs.cmds.get(member).unwrap().1, *member));
+                for member in component {
+                    Self::execute(rs, replica_id, member);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute(rs: &mut ReplicaState<S>, replica_id: u8, ins: Instance) {
+        Self::persist(rs, &LogRecord::Executed(ins));
+        // reply if you are the leader
+        if let Some((mes, addr, leader)) = Self::apply(rs, ins) {
+            if leader == replica_id {
+                rs.replies.push((mes, addr));
             }
-            _ => false,
         }
     }
 
-    pub fn add_dependency(dep_g: &mut DiGraph<Instance, ()>, src: Instance, dst: Instance) {
-        // Check if the nodes exist, if not, add them and get their node indices
-        let snode = match dep_g.node_indices().find(|&n| dep_g[n] == src) {
-            Some(node) => node,
-            None => dep_g.add_node(src),
+    // applies the command of ins to the state machine and marks ins executed, returns the reply to
+    // the client with its address and the command leader
+    fn apply(rs: &mut ReplicaState<S>, ins: Instance) -> Option<(ClientReply, SocketAddr, u8)> {
+        let (req, leader) = match rs.cmds.get(&ins).cloned() {
+            Some((r, _, _, _, l, _)) => (r, l),
+            None => return None,
+        };
+        // a request executed before is not applied again, its client gets the recorded reply
+        // unless it already received it
+        let mut recorded = None;
+        if let Some((_, (client, number, acked))) = req.client() {
+            let session = rs.clients.entry(client).or_default();
+            if acked > session.0 {
+                session.0 = acked;
+                session.1.retain(|n, _| *n >= acked);
+            }
+            if number < session.0 {
+                recorded = Some(None);
+            } else if let Some(res) = session.1.get(&number) {
+                recorded = Some(Some(res.clone()));
+            }
+        }
+
+        let result = match (req.clone(), recorded) {
+            (_, Some(res)) => res,
+            // the order checker compares the order of the commands that do not commute, on
+            // each of their keys
+            (ClientRequest::Command(cmd, _, _), None) => {
+                if rs.record_trace && cmd.access() == Access::Write {
+                    for key in cmd.keys() {
+                        rs.trace.push((ins, key));
+                    }
+                }
+                Some(rs.state.apply(&cmd))
+            }
+            (ClientRequest::NoOp, None) => None,
+            // every replica executes reconfigurations in the same order, so they all allocate
+            // the same ID; ballots of the new configuration are in a new epoch
+            (ClientRequest::AddReplica(new_addr, _, _), None) => {
+                let new_id = rs.next_id;
+                rs.next_id += 1;
+                rs.members.insert(new_id);
+                rs.epoch += 1;
+                rs.joined.push(new_addr);
+                Some(Some(new_id.to_string()))
+            }
+            (ClientRequest::RemoveReplica(old_id, _, _), None) => {
+                let removed = rs.members.remove(&old_id);
+                if removed {
+                    rs.epoch += 1;
+                    rs.left.push(old_id);
+                }
+                Some(removed.then(|| old_id.to_string()))
+            }
         };
-        let dnode = match dep_g.node_indices().find(|&n| dep_g[n] == dst) {
-            Some(node) => node,
-            None => dep_g.add_node(dst),
+        let reply = match (req.client(), result) {
+            (Some((addr, (client, number, _))), Some(res)) => {
+                if let Some(session) = rs.clients.get_mut(&client) {
+                    session.1.insert(number, res.clone());
+                }
+                Some((ClientReply::Reply(res, number), addr, leader))
+            }
+            _ => None,
         };
 
-        // Add the edge
-        dep_g.update_edge(snode, dnode, ());
+        // mark executed
+        rs.executed.insert(ins);
+        let mut prefix = rs.executed_prefix.get(&ins.0).cloned().unwrap_or(0);
+        while rs.executed.contains(&(ins.0, prefix + 1)) {
+            prefix += 1;
+        }
+        rs.executed_prefix.insert(ins.0, prefix);
+
+        reply
     }
 
-    pub async fn reply_to_client(message: ClientReply, addr: SocketAddr) -> () {
-        match Async::<TcpStream>::connect(addr).await {
-            Ok(mut stream) => {
-                // Intro messages.
-                println!(
-                    "Replying to client: {}",
-                    stream.get_ref().peer_addr().ok().unwrap()
-                );
-                let _ = stream
-                    .write_all(serde_json::to_string(&message).ok().unwrap().as_bytes())
-                    .await;
-                let _ = stream.write_all("\n".as_bytes()).await;
+    // appends record to the write-ahead log, if any, before the replica acts on the change
+    fn persist(rs: &mut ReplicaState<S>, record: &LogRecord<S::Command>) {
+        if let Some(wal) = rs.wal.as_mut() {
+            if let Err(e) = wal.append(record) {
+                Self::wal_failed(rs, e);
             }
-            Err(_) => {
-                println!("Connection to client address {} failed", addr);
+        }
+    }
+
+    // rewrites the write-ahead log as the records that rebuild the current state: a snapshot of
+    // the state up to the collected instances, then the instances still in progress
+    fn compact_log(rs: &mut ReplicaState<S>) {
+        if rs.wal.is_none() {
+            return;
+        }
+        let above = |ins: &Instance| ins.1 > rs.collected.get(&ins.0).cloned().unwrap_or(0);
+        let snapshot = Snapshot {
+            state: rs.state.snapshot(),
+            prefix: rs.collected.clone(),
+            executed: rs
+                .executed
+                .iter()
+                .filter(|ins| above(ins))
+                .cloned()
+                .collect(),
+            committed: rs
+                .cmds
+                .iter()
+                .filter(|(ins, cmd)| above(ins) && cmd.3 == CommandState::Committed)
+                .map(|(ins, (req, seq, deps, _, leader, _))| {
+                    (*ins, req.clone(), *seq, deps.clone(), *leader)
+                })
+                .collect(),
+            configuration: (rs.members.clone(), rs.next_id, rs.epoch),
+            clients: rs.clients.clone(),
+        };
+        let mut records = vec![LogRecord::Snapshot(snapshot)];
+        for (ins, (req, seq, deps, state, leader, ballot)) in rs.cmds.iter() {
+            if *state != CommandState::Committed {
+                let (req, deps, state) = (req.clone(), deps.clone(), state.clone());
+                records.push(LogRecord::State(
+                    *ins, req, *seq, deps, state, *leader, *ballot,
+                ));
+            }
+        }
+        records.extend(rs.as_proposed.iter().map(|ins| LogRecord::AsProposed(*ins)));
+        records.extend(
+            rs.ballots
+                .iter()
+                .filter(|(ins, _)| above(ins))
+                .map(|(ins, ballot)| LogRecord::Promise(*ins, *ballot)),
+        );
+
+        if let Some(wal) = rs.wal.as_mut() {
+            if let Err(e) = wal.rewrite(&records) {
+                Self::wal_failed(rs, e);
             }
         }
     }
 
-    pub fn execute_command(replica_state: Arc<Mutex<ReplicaState>>, replica_id: u8) -> () {
+    fn wal_failed(rs: &mut ReplicaState<S>, e: io::Error) {
+        rs.wal_failure
+            .get_or_insert(format!("Write-ahead log failed: {}", e));
+    }
+
+    pub fn atomic_sync_log(replica_state: Arc<Mutex<ReplicaState<S>>>) {
         let mut rs = replica_state.lock().unwrap();
+        if let Some(wal) = rs.wal.as_mut() {
+            if let Err(e) = wal.sync() {
+                Self::wal_failed(&mut rs, e);
+            }
+        }
+        drop(rs);
+    }
 
-        let sccs = kosaraju_scc(&rs.dep_graph);
-        for mut sc in sccs {
-            // this is in reverse topological order
-            // sort each connected comp by seq number
-            sc.sort_by(|a, b| {
-                let a_node = rs.dep_graph.node_weight(*a).unwrap();
-                let b_node = rs.dep_graph.node_weight(*b).unwrap();
-                let a_seq = rs.cmds.get(a_node).unwrap().1;
-                let b_seq = rs.cmds.get(b_node).unwrap().1;
-                a_seq.cmp(&b_seq)
-            });
-            // execute unexecuted commands in this order
-            for node in sc {
-                let ins = rs.dep_graph.node_weight(node).unwrap().clone();
-                let (req, leader) = match rs.cmds.get(&ins).cloned() {
-                    Some((r, _, _, _, l)) => (r, l),
-                    None => return (),
-                };
-                // execute if not already executed
-                if !rs.executed.contains(&ins) {
-                    let (res, addr, id) = match req.clone() {
-                        ClientRequest::Read(key, addr, id) => {
-                            (rs.dict.get(&key).cloned(), addr, id)
-                        }
-                        ClientRequest::Write(key, val, addr, id) => {
-                            (rs.dict.insert(key, val), addr, id)
-                        }
-                    };
-                    // reply if you are the leader
-                    if leader == replica_id {
-                        let mes: ClientReply = ClientReply::Reply(res, id);
-                        smol::spawn(Replica::reply_to_client(mes, addr)).detach();
+    // error of the write-ahead log, if a change could not be logged
+    pub fn atomic_wal_failure(replica_state: Arc<Mutex<ReplicaState<S>>>) -> Option<String> {
+        let rs = replica_state.lock().unwrap();
+        let failure = rs.wal_failure.clone();
+        drop(rs);
+        failure
+    }
+
+    // rebuilds the state from the records of the write-ahead log, which then logs the changes
+    // that follow, returns the committed instances that were not executed yet
+    pub fn atomic_replay(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        records: Vec<LogRecord<S::Command>>,
+        wal: Wal,
+    ) -> Vec<Instance> {
+        let mut rs = replica_state.lock().unwrap();
+        for record in records {
+            match record {
+                LogRecord::State(ins, req, seq, deps, state, leader, ballot) => {
+                    let promised = max(Self::ballot(&rs, ins), ballot);
+                    rs.ballots.insert(ins, promised);
+                    if ins.0 == replica_id {
+                        rs.instance_number = max(rs.instance_number, ins.1);
+                    }
+                    Self::update_state(&mut rs, req, seq, deps, ins, state, leader);
+                }
+                LogRecord::Promise(ins, ballot) => {
+                    let promised = max(Self::ballot(&rs, ins), ballot);
+                    rs.ballots.insert(ins, promised);
+                }
+                LogRecord::AsProposed(ins) => {
+                    rs.as_proposed.insert(ins);
+                }
+                LogRecord::Executed(ins) => {
+                    Self::apply(&mut rs, ins);
+                }
+                LogRecord::Collected(r, upto) => {
+                    let from = rs.collected.get(&r).cloned().unwrap_or(0);
+                    for i in from + 1..=upto {
+                        Self::collect(&mut rs, (r, i));
                     }
-                    // mark executed
-                    rs.executed.insert(ins);
+                    rs.collected.insert(r, max(from, upto));
+                }
+                LogRecord::Snapshot(snapshot) => {
+                    Self::install_snapshot(replica_id, &mut rs, snapshot);
                 }
             }
         }
-
+        Self::skip_used_instances(replica_id, &mut rs);
+        rs.wal = Some(wal);
+
+        let pending = rs
+            .cmds
+            .iter()
+            .filter(|(ins, cmd)| cmd.3 == CommandState::Committed && !rs.executed.contains(ins))
+            .map(|(ins, _)| *ins)
+            .collect();
         drop(rs);
+        pending
+    }
+
+    // true if ins was executed by every replica and removed from the state
+    pub fn is_collected(rs: &ReplicaState<S>, ins: Instance) -> bool {
+        ins.1 <= rs.collected.get(&ins.0).cloned().unwrap_or(0)
+    }
+
+    pub fn is_executed(rs: &ReplicaState<S>, ins: Instance) -> bool {
+        rs.executed.contains(&ins) || Self::is_collected(rs, ins)
     }
 
     pub fn update_state(
-        rs: &mut std::sync::MutexGuard<ReplicaState>,
-        req: ClientRequest,
+        rs: &mut std::sync::MutexGuard<ReplicaState<S>>,
+        req: ClientRequest<S::Command>,
         cseq: SeqNumber,
         cdeps: HashSet<Instance>,
         cins: Instance,
         cmd_state: CommandState,
         leader: u8,
     ) {
-        rs.cmds
-            .insert(cins, (req.clone(), cseq, cdeps.clone(), cmd_state, leader));
-
-        // add dependencies to dep_graph
-        match rs
-            .dep_graph
-            .node_indices()
-            .find(|&n| rs.dep_graph[n] == cins)
-        {
-            Some(_) => (),
-            None => {
-                rs.dep_graph.add_node(cins);
+        // a collected instance is gone for good
+        if Self::is_collected(rs, cins) {
+            return;
+        }
+
+        // a committed instance is final, late or recovery messages must not roll it back
+        if let Some(cmd) = rs.cmds.get(&cins) {
+            if cmd.3 == CommandState::Committed && cmd_state != CommandState::Committed {
+                return;
             }
-        };
+        }
+        rs.as_proposed.remove(&cins);
+
+        // track progress of uncommitted instances and of dependencies we have not seen yet
+        let now = rs.clock.now();
+        if cmd_state == CommandState::Committed {
+            rs.last_progress.remove(&cins);
+            rs.recovering.remove(&cins);
+            rs.avoid_fast_path.remove(&cins);
+        } else {
+            rs.last_progress.insert(cins, now);
+        }
+        for d in cdeps.iter() {
+            if !rs.cmds.contains_key(d) && !Self::is_collected(rs, *d) {
+                rs.last_progress.entry(*d).or_insert(now);
+            }
+        }
+        // instances of the leader below cins that we never heard of were missed, e.g. while this
+        // replica was down, recovery learns them from the other replicas
+        let highest = rs.highest_seen.get(&cins.0).cloned().unwrap_or(0);
+        for i in highest + 1..cins.1 {
+            let missed = (cins.0, i);
+            if !rs.cmds.contains_key(&missed) && !Self::is_collected(rs, missed) {
+                rs.last_progress.entry(missed).or_insert(now);
+            }
+        }
+        rs.highest_seen.insert(cins.0, max(highest, cins.1));
+
+        // the state is accepted at the ballot promised for the instance, which the message
+        // handlers have raised to the ballot of the message
+        let ballot = Self::ballot(rs, cins);
+        if rs.wal.is_some() {
+            let record = LogRecord::State(
+                cins,
+                req.clone(),
+                cseq,
+                cdeps.clone(),
+                cmd_state.clone(),
+                leader,
+                ballot,
+            );
+            Self::persist(rs, &record);
+        }
+        rs.cmds.insert(
+            cins,
+            (req.clone(), cseq, cdeps.clone(), cmd_state, leader, ballot),
+        );
+
+        // replace the dependencies of cins in dep_graph, attributes can change until commit
+        let node = Self::node(rs, cins);
+        let old_deps: Vec<_> = rs.dep_graph.neighbors(node).collect();
+        for d in old_deps {
+            if let Some(edge) = rs.dep_graph.find_edge(node, d) {
+                rs.dep_graph.remove_edge(edge);
+            }
+        }
+        // collected dependencies have been executed everywhere
         for d in cdeps.clone() {
-            Replica::add_dependency(&mut rs.dep_graph, cins, d);
+            if !Self::is_collected(rs, d) {
+                Self::add_dependency(rs, cins, d);
+            }
+        }
+
+        // index the instance under its key
+        if req.is_reconfiguration() {
+            let i = rs.latest_reconfig.entry(cins.0).or_insert(cins.1);
+            *i = max(*i, cins.1);
+        }
+        if let Some((_, (client, _, _))) = req.client() {
+            let latest = rs.latest_client.entry(client).or_default();
+            let i = latest.entry(cins.0).or_insert(cins.1);
+            *i = max(*i, cins.1);
+        }
+        for key in req.keys() {
+            let latest = rs.latest.entry(key.clone()).or_default();
+            let i = latest.entry(cins.0).or_insert(cins.1);
+            *i = max(*i, cins.1);
+            if req.access() != Access::Read {
+                let latest = rs.latest_write.entry(key.clone()).or_default();
+                let i = latest.entry(cins.0).or_insert(cins.1);
+                *i = max(*i, cins.1);
+            }
+            if req.access() != Access::Commutative {
+                let latest = rs.latest_non_commutative.entry(key.clone()).or_default();
+                let i = latest.entry(cins.0).or_insert(cins.1);
+                *i = max(*i, cins.1);
+            }
         }
     }
 
-    pub fn atomic_request_preaccept(
-        replica_id: u8,
-        replica_state: Arc<Mutex<ReplicaState>>,
-        req: ClientRequest,
+    // seq and deps of req given the proposed attributes and the latest interfering instance of
+    // every replica: a write depends on the latest request on its keys, a read on the latest
+    // write, a commutative update on the latest request that is not one, and both on the latest
+    // request of their own leader, so that every earlier interfering instance is a transitive
+    // dependency and the cost does not depend on the size of the log
+    pub fn attributes(
+        rs: &ReplicaState<S>,
+        req: &ClientRequest<S::Command>,
         cseq: SeqNumber,
         cdeps: HashMap<Instance, bool>,
-        cins: Option<Instance>,
-        leader_id: u8,
-    ) -> (SeqNumber, HashMap<Instance, bool>, Instance) {
-        let mut rs = replica_state.lock().unwrap();
-
-        let mut seq = cseq;
+        cins: Instance,
+    ) -> (SeqNumber, HashMap<Instance, bool>) {
         let mut deps = cdeps;
 
         // check if I have committed any existing dependencies
         for (k, v) in deps.clone().into_iter() {
             if !v {
-                match rs.cmds.get(&k).cloned() {
-                    Some(cmd) => {
-                        if cmd.3 != CommandState::PreAccepted {
-                            deps.insert(k, true);
-                        }
+                if let Some(cmd) = rs.cmds.get(&k) {
+                    if cmd.3 != CommandState::PreAccepted {
+                        deps.insert(k, true);
                     }
-                    None => (),
+                } else if Self::is_collected(rs, k) {
+                    deps.insert(k, true);
                 }
             }
         }
 
-        for (i, (ireq, sn, _, status, _)) in rs.cmds.clone().into_iter() {
-            if Replica::interfere(req.clone(), ireq) {
-                seq = max(seq, 1 + sn);
-                if status == CommandState::PreAccepted {
-                    deps.insert(i, false);
-                } else {
-                    deps.insert(i, true);
+        // a reconfiguration depends on the latest instance of every replica, and every command on
+        // the latest reconfiguration of every replica
+        let mut conflicts: Vec<Instance> = Vec::new();
+        if req.is_reconfiguration() {
+            conflicts.extend(rs.highest_seen.iter().map(|(r, i)| (*r, *i)));
+        } else if !req.keys().is_empty() {
+            conflicts.extend(rs.latest_reconfig.iter().map(|(r, i)| (*r, *i)));
+        }
+        if let Some(latest) = req.client().and_then(|(_, id)| rs.latest_client.get(&id.0)) {
+            conflicts.extend(latest.iter().map(|(r, i)| (*r, *i)));
+        }
+
+        for key in req.keys() {
+            let latest = rs.latest.get(&key);
+            let interfering = match req.access() {
+                Access::Write => {
+                    conflicts.extend(latest.into_iter().flatten().map(|(r, i)| (*r, *i)));
+                    continue;
                 }
+                Access::Read => rs.latest_write.get(&key),
+                Access::Commutative => rs.latest_non_commutative.get(&key),
+            };
+            conflicts.extend(interfering.into_iter().flatten().map(|(r, i)| (*r, *i)));
+            if let Some(i) = latest.and_then(|latest| latest.get(&cins.0)) {
+                conflicts.push((cins.0, *i));
             }
         }
 
-        let deps_keys: HashSet<Instance> = deps.keys().cloned().collect();
-        match cins {
-            Some(cins) => {
-                // replica_id is not command leader
-                Replica::update_state(
-                    &mut rs,
-                    req,
-                    seq,
-                    deps_keys,
-                    cins,
-                    CommandState::PreAccepted,
-                    leader_id,
-                );
-
-                // if replica_id is not the command leader, then no need to track number of preaccepts
+        Self::add_conflicts(rs, cseq, deps, cins, conflicts)
+    }
 
-                drop(rs);
+    fn add_conflicts(
+        rs: &ReplicaState<S>,
+        cseq: SeqNumber,
+        cdeps: HashMap<Instance, bool>,
+        cins: Instance,
+        conflicts: Vec<Instance>,
+    ) -> (SeqNumber, HashMap<Instance, bool>) {
+        let mut seq = cseq;
+        let mut deps = cdeps;
+        for i in conflicts {
+            // the leader already included its own earlier instances
+            if i.0 == cins.0 && i.1 >= cins.1 {
+                continue;
+            }
+            if let Some((_, sn, _, status, _, _)) = rs.cmds.get(&i) {
+                seq = max(seq, 1 + sn);
+                deps.insert(i, *status != CommandState::PreAccepted);
+            }
+        }
+
+        (seq, deps)
+    }
+
+    pub fn atomic_request_preaccept(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        req: ClientRequest<S::Command>,
+        cseq: SeqNumber,
+        cdeps: HashMap<Instance, bool>,
+        cins: Option<Instance>,
+        leader_id: u8,
+    ) -> (SeqNumber, HashMap<Instance, bool>, Instance) {
+        let mut rs = replica_state.lock().unwrap();
+
+        // a new instance is the next one of replica_id
+        let ins = cins.unwrap_or((replica_id, rs.instance_number + 1));
+        let proposed: HashSet<Instance> = cdeps.keys().cloned().collect();
+        let (seq, deps) = Self::attributes(&rs, &req, cseq, cdeps, ins);
+
+        let deps_keys: HashSet<Instance> = deps.keys().cloned().collect();
+        match cins {
+            Some(cins) => {
+                // replica_id is not command leader
+                Self::update_state(
+                    &mut rs,
+                    req,
+                    seq,
+                    deps_keys,
+                    cins,
+                    CommandState::PreAccepted,
+                    leader_id,
+                );
+                // no conflict the leader did not know of, this replica may be in a fast quorum
+                if seq == cseq && deps.keys().cloned().collect::<HashSet<_>>() == proposed {
+                    rs.as_proposed.insert(cins);
+                    Self::persist(&mut rs, &LogRecord::AsProposed(cins));
+                }
+
+                // if replica_id is not the command leader, then no need to track number of preaccepts
+
+                drop(rs);
 
                 println!("{}{:?}{:?}", seq, deps, cins);
 
@@ -425,7 +1346,13 @@ impl Replica {
                 // replica_id is command leader
                 rs.instance_number += 1;
                 let ins = rs.instance_number;
-                Replica::update_state(
+                // the initial ballot is in the current epoch, and the instance keeps it when the
+                // configuration changes
+                let epoch = rs.epoch;
+                rs.ballots
+                    .entry((replica_id, ins))
+                    .or_insert((epoch, 0, replica_id));
+                Self::update_state(
                     &mut rs,
                     req,
                     seq,
@@ -435,7 +1362,8 @@ impl Replica {
                     leader_id,
                 );
 
-                rs.preaccept_replies.insert((replica_id, ins), Vec::new());
+                rs.preaccept_replies
+                    .insert((replica_id, ins), Default::default());
                 drop(rs);
 
                 println!("{}{:?}{:?}", seq, deps, (replica_id, ins));
@@ -445,202 +1373,175 @@ impl Replica {
         }
     }
 
+    // number of failures a cluster of n replicas tolerates
+    pub fn max_failures(n: u8) -> u8 {
+        (n - 1) / 2
+    }
+
+    // an even number of replicas, e.g. in the middle of a reconfiguration, has one replica more
+    // than 2F + 1 and every quorum grows by one so that quorums still intersect
+    pub fn extra(n: u8) -> u8 {
+        (n + 1) % 2
+    }
+
+    // replicas, command leader included, needed to commit on the fast path: F + floor((F + 1) / 2),
+    // never fewer than the slow path needs (with F = 0 the formula would leave out the leader)
+    pub fn fast_quorum(n: u8) -> u8 {
+        let f = Self::max_failures(n);
+        (f + f.div_ceil(2) + Self::extra(n)).max(Self::slow_quorum(n))
+    }
+
+    // replicas, command leader included, needed to commit on the slow path: F + 1
+    pub fn slow_quorum(n: u8) -> u8 {
+        Self::max_failures(n) + 1 + Self::extra(n)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn path(
         n: u8,
-        replica_state: Arc<Mutex<ReplicaState>>,
-        req: ClientRequest,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        req: ClientRequest<S::Command>,
         cseq: SeqNumber,
         cdeps: HashMap<Instance, bool>,
         cins: Instance,
         leader_id: u8,
+        sender: SocketAddr,
     ) -> Option<(SeqNumber, HashSet<Instance>, bool)> {
         let mut rs = replica_state.lock().unwrap();
-        let preaccept_replies = rs.preaccept_replies.get(&cins).cloned();
-        match preaccept_replies {
-            Some(mut preaccept_replies) => {
-                // add preaccept reply to list of preaccept replies
-                preaccept_replies.push((cseq, cdeps.clone()));
-                rs.preaccept_replies.insert(cins, preaccept_replies.clone());
-
-                if preaccept_replies.len() >= (n / 2).into() {
-                    match rs.cmds.get(&cins).cloned() {
-                        Some(cmd) => {
-                            // if committed or executed, then this is a late PreAcceptOK
-                            if cmd.3 == CommandState::PreAccepted {
-                                if n == 3 {
-                                    // always take fast path and commit
-                                    let cdeps_keys: HashSet<Instance> =
-                                        cdeps.keys().cloned().collect();
-                                    Replica::update_state(
-                                        &mut rs,
-                                        req,
-                                        cseq,
-                                        cdeps_keys.clone(),
-                                        cins,
-                                        CommandState::Committed,
-                                        leader_id,
-                                    );
-                                    drop(rs);
-
-                                    println!("{}", Replica::format_log(n, replica_state.clone()));
-
-                                    return Some((cseq, cdeps_keys, true));
-                                } else {
-                                    // n = 5
-                                    // to take fast path when n = 5, every dependence must have some replica in the
-                                    // quorum that has committed or executed it
-
-                                    // take max of seq and union of deps
-                                    let mut same = true;
-                                    let mut union = preaccept_replies[0].clone();
-                                    for reply in &preaccept_replies[1..] {
-                                        // if seq numbers differ, then no fast path
-                                        if reply.0 != union.0 {
-                                            same = false;
-                                            // at this point, not going to take fast path
-                                            // when taking slow path, need to update seq to greatest seq seen
-                                            if reply.0 > union.0 {
-                                                union.0 = reply.0
-                                            }
-                                        }
-
-                                        for (k, v) in reply.1.clone().into_iter() {
-                                            match union.1.get(&k).cloned() {
-                                                // key in union so dependence already in union
-                                                Some(v_) => {
-                                                    // replica has committed the dependence but the leader has not
-                                                    // so update to reflect that someone has committed the dependence
-                                                    if (v == true) && (v_ == false) {
-                                                        union.1.insert(k, v);
-                                                    }
-                                                }
-                                                // key not in union so some replica had a different dependence
-                                                None => {
-                                                    same = false;
-                                                    union.1.insert(k, v);
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    if same {
-                                        // check whether leader has committed any dependencies that the other
-                                        // quorum replicas have not
-                                        for (k, v) in union.1.clone().into_iter() {
-                                            if !v {
-                                                match rs.cmds.get(&k).cloned() {
-                                                    Some(cmd_) => {
-                                                        if cmd_.3 == CommandState::PreAccepted {
-                                                            // no one has committed this dependence
-                                                            // so must take slow path
-
-                                                            // [FIXME] update command state to accepted
-                                                            let union_keys: HashSet<Instance> =
-                                                                union.1.keys().cloned().collect();
-                                                            Replica::update_state(
-                                                                &mut rs,
-                                                                req,
-                                                                union.0,
-                                                                union_keys.clone(),
-                                                                cins,
-                                                                CommandState::Accepted,
-                                                                leader_id,
-                                                            );
-                                                            drop(rs);
-                                                            return Some((
-                                                                union.0, union_keys, false,
-                                                            ));
-                                                        } else {
-                                                            union.1.insert(k, true);
-                                                        }
-                                                    }
-                                                    None => {
-                                                        // no one has committed this dependence
-                                                        // so must take slow path
-                                                        let union_keys: HashSet<Instance> =
-                                                            union.1.keys().cloned().collect();
-                                                        Replica::update_state(
-                                                            &mut rs,
-                                                            req,
-                                                            union.0,
-                                                            union_keys.clone(),
-                                                            cins,
-                                                            CommandState::Accepted,
-                                                            leader_id,
-                                                        );
-                                                        drop(rs);
-                                                        return Some((
-                                                            union.0,
-                                                            union.1.keys().cloned().collect(),
-                                                            false,
-                                                        ));
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        // all seq and deps are the same, and every dep has some replica that has
-                                        // committed it; take fast path
-                                        let union_keys: HashSet<Instance> =
-                                            union.1.keys().cloned().collect();
-                                        Replica::update_state(
-                                            &mut rs,
-                                            req,
-                                            union.0,
-                                            union_keys.clone(),
-                                            cins,
-                                            CommandState::Committed,
-                                            leader_id,
-                                        );
-                                        drop(rs);
-
-                                        println!(
-                                            "{}",
-                                            Replica::format_log(n, replica_state.clone())
-                                        );
-                                        return Some((union.0, union_keys, true));
-                                    } else {
-                                        // not same seq and deps, take slow path 
-                                        let union_keys : HashSet<Instance> = union.1.keys().cloned().collect();
-                                        Replica::update_state(&mut rs, req, union.0, union_keys.clone(), cins, CommandState::Accepted, leader_id);
-                                        drop(rs);
-                                        return Some((
-                                            union.0,
-                                            union.1.keys().cloned().collect(),
-                                            false,
-                                        ));
-                                    }
-                                }
-                            } else {
-                                drop(rs);
-                                return None;
-                            }
-                        }
-                        None => {
-                            // this part of the code should never be reached
-                            drop(rs);
-                            println!("PreAcceptOK received before any PreAccept sent");
-                            return None;
-                        }
-                    }
-                } else {
-                    // insufficient number of PreAcceptOKs
-                    drop(rs);
-                    return None;
-                }
-            }
+        let (mut senders, mut preaccept_replies) = match rs.preaccept_replies.get(&cins).cloned() {
+            Some(preaccept_replies) => preaccept_replies,
             None => {
-                // this part of the code should never be reached
+                // late reply to an instance we have stopped leading, otherwise never reached
+                drop(rs);
                 println!("PreAcceptOK received before any PreAccept sent");
+                return None;
+            }
+        };
+
+        // a replica that already replied, e.g. a duplicated message, is not counted again
+        if !senders.insert(sender) {
+            drop(rs);
+            return None;
+        }
+
+        // add preaccept reply to list of preaccept replies
+        preaccept_replies.push((cseq, cdeps));
+        rs.preaccept_replies
+            .insert(cins, (senders, preaccept_replies.clone()));
+
+        // the command leader is part of the fast quorum
+        if preaccept_replies.len() + 1 < usize::from(Self::fast_quorum(n)) {
+            // insufficient number of PreAcceptOKs
+            drop(rs);
+            return None;
+        }
+
+        let (proposed_seq, proposed_deps) = match rs.cmds.get(&cins) {
+            // if committed or accepted, then this is a late PreAcceptOK
+            Some(cmd) if cmd.3 == CommandState::PreAccepted => (cmd.1, cmd.2.clone()),
+            _ => {
                 drop(rs);
                 return None;
             }
+        };
+
+        // take max of seq and union of deps, the fast path needs identical attributes in every
+        // reply of the fast quorum
+        let mut same = true;
+        let mut union = preaccept_replies[0].clone();
+        for reply in &preaccept_replies[1..] {
+            // if seq numbers differ, then no fast path
+            if reply.0 != union.0 {
+                same = false;
+                // when taking slow path, need to update seq to greatest seq seen
+                union.0 = max(union.0, reply.0);
+            }
+
+            for (k, v) in reply.1.iter() {
+                match union.1.get(k) {
+                    // replica has committed the dependence but the others have not, so update
+                    // to reflect that someone has committed the dependence
+                    Some(v_) => {
+                        if *v && !v_ {
+                            union.1.insert(*k, true);
+                        }
+                    }
+                    // key not in union so some replica had a different dependence
+                    None => {
+                        same = false;
+                        union.1.insert(*k, *v);
+                    }
+                }
+            }
+        }
+        for reply in &preaccept_replies[1..] {
+            if reply.1.len() != union.1.len() {
+                same = false;
+            }
+        }
+        // the replies must also keep the attributes the leader proposed, only those replies are
+        // told apart by recovery
+        if union.0 != proposed_seq
+            || union.1.keys().cloned().collect::<HashSet<_>>() != proposed_deps
+        {
+            same = false;
+        }
+
+        // with more than 3 replicas the fast quorum is smaller than 2F, so every dependence
+        // must also have been committed by some replica in the quorum
+        if same && n > 3 {
+            for (k, v) in union.1.clone().into_iter() {
+                if !v {
+                    match rs.cmds.get(&k) {
+                        Some(cmd) if cmd.3 != CommandState::PreAccepted => {
+                            union.1.insert(k, true);
+                        }
+                        None if Self::is_collected(&rs, k) => {
+                            union.1.insert(k, true);
+                        }
+                        // no one has committed this dependence so must take slow path
+                        _ => same = false,
+                    }
+                }
+            }
         }
+
+        // instances re-proposed by recovery always take the slow path
+        let take_fast = same && !rs.avoid_fast_path.contains(&cins);
+        let union_keys: HashSet<Instance> = union.1.keys().cloned().collect();
+        if take_fast {
+            rs.fast_commits += 1;
+            Self::update_state(
+                &mut rs,
+                req,
+                union.0,
+                union_keys.clone(),
+                cins,
+                CommandState::Committed,
+                leader_id,
+            );
+            drop(rs);
+        } else {
+            Self::update_state(
+                &mut rs,
+                req,
+                union.0,
+                union_keys.clone(),
+                cins,
+                CommandState::Accepted,
+                leader_id,
+            );
+            rs.naccept.insert(cins, HashSet::new());
+            rs.slow_commits += 1;
+            drop(rs);
+        }
+
+        Some((union.0, union_keys, take_fast))
     }
 
     pub fn atomic_update_state(
-        replica_state: Arc<Mutex<ReplicaState>>,
-        req: ClientRequest,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        req: ClientRequest<S::Command>,
         cseq: u64,
         cdeps: HashSet<(u8, u64)>,
         cins: (u8, u64),
@@ -648,18 +1549,20 @@ impl Replica {
         leader_id: u8,
     ) -> () {
         let mut rs = replica_state.lock().unwrap();
-        Replica::update_state(&mut rs, req, cseq, cdeps, cins, cmd_state, leader_id);
+        Self::update_state(&mut rs, req, cseq, cdeps, cins, cmd_state, leader_id);
         drop(rs);
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn atomic_accept(
         n: u8,
-        replica_state: Arc<Mutex<ReplicaState>>,
-        req: ClientRequest,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        req: ClientRequest<S::Command>,
         cseq: SeqNumber,
         cdeps: HashSet<Instance>,
         cins: Instance,
         leader_id: u8,
+        sender: SocketAddr,
     ) -> bool {
         let mut rs = replica_state.lock().unwrap();
         match rs.cmds.get(&cins).cloned() {
@@ -669,13 +1572,14 @@ impl Replica {
                     drop(rs);
                     return false;
                 } else {
-                    let naccept = rs.naccept.get(&cins).cloned();
-                    match naccept {
+                    match rs.naccept.get_mut(&cins) {
                         Some(naccept) => {
-                            rs.naccept.insert(cins, naccept + 1);
-                            if naccept >= n / 2 {
+                            // a replica that already replied is not counted again
+                            naccept.insert(sender);
+                            // the command leader is part of the slow quorum
+                            if naccept.len() + 1 >= usize::from(Self::slow_quorum(n)) {
                                 // commit
-                                Replica::update_state(
+                                Self::update_state(
                                     &mut rs,
                                     req,
                                     cseq,
@@ -685,8 +1589,6 @@ impl Replica {
                                     leader_id,
                                 );
                                 drop(rs);
-
-                                println!("{}", Replica::format_log(n, replica_state.clone()));
                                 return true;
                             } else {
                                 drop(rs);
@@ -711,306 +1613,756 @@ impl Replica {
         }
     }
 
-    pub async fn dispatch(
+    // highest ballot promised for ins; if none was promised yet, the lowest ballot of its leader,
+    // so that a leader still in an older epoch is not refused its initial ballot
+    pub fn ballot(rs: &ReplicaState<S>, ins: Instance) -> Ballot {
+        match rs.ballots.get(&ins) {
+            Some(ballot) => *ballot,
+            None => (0, 0, ins.0),
+        }
+    }
+
+    // promises ballot for ins, durably if the replica has a write-ahead log
+    fn promise(rs: &mut ReplicaState<S>, ins: Instance, ballot: Ballot) {
+        Self::persist(rs, &LogRecord::Promise(ins, ballot));
+        rs.ballots.insert(ins, ballot);
+    }
+
+    pub fn atomic_ballot(replica_state: Arc<Mutex<ReplicaState<S>>>, ins: Instance) -> Ballot {
+        let rs = replica_state.lock().unwrap();
+        let ballot = Self::ballot(&rs, ins);
+        drop(rs);
+        ballot
+    }
+
+    // promises ballot for ins unless a higher ballot was already promised, in which case the
+    // promised ballot is returned so the sender can be NACKed
+    pub fn atomic_check_ballot(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        ballot: Ballot,
+        ins: Instance,
+    ) -> Result<(), Ballot> {
+        let mut rs = replica_state.lock().unwrap();
+        let promised = Self::ballot(&rs, ins);
+        if ballot < promised {
+            drop(rs);
+            return Err(promised);
+        }
+        if ballot > promised {
+            Self::promise(&mut rs, ins, ballot);
+        } else {
+            // keep the epoch the instance started in when the configuration changes
+            rs.ballots.entry(ins).or_insert(ballot);
+        }
+        drop(rs);
+        Ok(())
+    }
+
+    // another replica has promised a higher ballot for ins: stop leading the instance and
+    // remember the ballot so that a later recovery picks a higher one
+    pub fn atomic_nack(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        promised: Ballot,
+        ins: Instance,
+    ) {
+        let mut rs = replica_state.lock().unwrap();
+        if promised > Self::ballot(&rs, ins) {
+            Self::promise(&mut rs, ins, promised);
+            rs.preaccept_replies.remove(&ins);
+            rs.naccept.remove(&ins);
+            rs.recovering.remove(&ins);
+        }
+        drop(rs);
+    }
+
+    // what this replica knows about ins and the ballot it was accepted at
+    fn instance_info(rs: &ReplicaState<S>, ins: Instance) -> AcceptedInfo<S::Command> {
+        match rs.cmds.get(&ins) {
+            Some((req, seq, deps, state, _, ballot)) => (
+                Some((req.clone(), *seq, deps.clone(), state.clone())),
+                *ballot,
+                rs.as_proposed.contains(&ins),
+            ),
+            None => (None, (0, 0, ins.0), false),
+        }
+    }
+
+    fn start_recovery(
         replica_id: u8,
-        replica_addr: SocketAddr,
+        rs: &mut std::sync::MutexGuard<ReplicaState<S>>,
+        ins: Instance,
+    ) -> Ballot {
+        let promised = Self::ballot(rs, ins);
+        let ballot = (max(promised.0, rs.epoch), promised.1 + 1, replica_id);
+
+        // promise the new ballot locally and count our own state as the first PrepareOK
+        Self::promise(rs, ins, ballot);
+        let (info, accepted, as_proposed) = Self::instance_info(rs, ins);
+        rs.recovering.insert(
+            ins,
+            (ballot, vec![(info, accepted, as_proposed, replica_id)]),
+        );
+        let now = rs.clock.now();
+        rs.last_progress.insert(ins, now);
+
+        ballot
+    }
+
+    // starts recovery of every instance that has not made progress within timeout, returns the
+    // instances with the ballots to send in Prepare; leaders the failure detector still hears
+    // from are given twice the timeout to finish their instances; a leader recovers its own
+    // instances after timeout, since no other replica may know those whose messages were lost
+    pub fn atomic_recovery_candidates(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        detector: Arc<Mutex<FailureDetector>>,
+        timeout: Duration,
+    ) -> Vec<(Instance, Ballot)> {
+        let fd = detector.lock().unwrap();
+        let mut rs = replica_state.lock().unwrap();
+
+        let stalled: Vec<Instance> = rs
+            .last_progress
+            .iter()
+            .filter(|(ins, t)| {
+                let grace = if ins.0 == replica_id || fd.is_replica_suspected(ins.0) {
+                    timeout
+                } else {
+                    timeout * 2
+                };
+                rs.clock.since(**t) >= grace
+            })
+            .map(|(ins, _)| *ins)
+            .collect();
+        drop(fd);
+
+        let mut prepares = Vec::new();
+        for ins in stalled {
+            let ballot = Self::start_recovery(replica_id, &mut rs, ins);
+            prepares.push((ins, ballot));
+        }
+        drop(rs);
+
+        prepares
+    }
+
+    // promises ballot for ins if it is higher than any ballot seen so far, returns what this
+    // replica knows about ins together with the ballot it was accepted at, or the promised
+    // ballot if the Prepare is stale
+    pub fn atomic_prepare(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        ballot: Ballot,
+        ins: Instance,
+    ) -> Result<AcceptedInfo<S::Command>, Ballot> {
+        let mut rs = replica_state.lock().unwrap();
+
+        let promised = Self::ballot(&rs, ins);
+        if ballot <= promised {
+            drop(rs);
+            return Err(promised);
+        }
+        Self::promise(&mut rs, ins, ballot);
+
+        // our own recovery or leadership with a lower ballot can no longer succeed
+        rs.preaccept_replies.remove(&ins);
+        rs.naccept.remove(&ins);
+        rs.recovering.remove(&ins);
+        // give the new recovery time before trying ourselves
+        if rs.last_progress.contains_key(&ins) {
+            let now = rs.clock.now();
+            rs.last_progress.insert(ins, now);
+        }
+
+        let reply = Self::instance_info(&rs, ins);
+        drop(rs);
+
+        Ok(reply)
+    }
+
+    // records a PrepareOK and, once a majority replied, decides how to finish the instance
+    #[allow(clippy::too_many_arguments)]
+    pub fn atomic_prepare_ok(
         n: u8,
-        replica_state: Arc<Mutex<ReplicaState>>,
-        receiver: Receiver<Event>,
-        mut streams: HashMap<SocketAddr, Async<TcpStream>>,
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        ballot: Ballot,
+        ins: Instance,
+        reply: AcceptedInfo<S::Command>,
+        sender_id: u8,
+    ) -> Option<Recovery<S::Command>> {
+        let mut rs = replica_state.lock().unwrap();
+
+        let mut replies = match rs.recovering.get(&ins) {
+            Some((b, replies)) if *b == ballot => replies.clone(),
+            // late reply, or reply to a recovery we have given up on
+            _ => {
+                drop(rs);
+                return None;
+            }
+        };
+        // a replica that already replied, e.g. a duplicated message, is not counted again
+        if replies.iter().any(|(_, _, _, id)| *id == sender_id) {
+            drop(rs);
+            return None;
+        }
+        let (info, accepted, as_proposed) = reply;
+        replies.push((info, accepted, as_proposed, sender_id));
+        if replies.len() < usize::from(Self::slow_quorum(n)) {
+            rs.recovering.insert(ins, (ballot, replies));
+            drop(rs);
+            return None;
+        }
+        rs.recovering.remove(&ins);
+
+        // committed anywhere: the attributes are final
+        let committed = replies.iter().find_map(|(info, _, _, _)| match info {
+            Some((req, seq, deps, CommandState::Committed)) => {
+                Some((req.clone(), *seq, deps.clone()))
+            }
+            _ => None,
+        });
+        if let Some((req, seq, deps)) = committed {
+            Self::update_state(
+                &mut rs,
+                req.clone(),
+                seq,
+                deps.clone(),
+                ins,
+                CommandState::Committed,
+                replica_id,
+            );
+            drop(rs);
+            return Some(Recovery::Commit(req, seq, deps));
+        }
+
+        // otherwise only the replies with the highest ballot count
+        let highest = replies.iter().map(|r| r.1).max().unwrap();
+        let current: Vec<&InstanceInfo<S::Command>> = replies
+            .iter()
+            .filter(|r| r.1 == highest)
+            .filter_map(|r| r.0.as_ref())
+            .collect();
+
+        // accepted by someone: finish the Paxos-Accept phase with those attributes
+        let mut accept = current
+            .iter()
+            .find(|info| info.3 == CommandState::Accepted)
+            .map(|info| (info.0.clone(), info.1, info.2.clone()));
+
+        // at least floor((F + 1) / 2) replies PreAccepted for the initial ballot with the
+        // attributes the leader proposed, none of them from the leader: the command may have
+        // committed on the fast path, since that many replicas of any fast quorum are in every
+        // majority without the leader; they all carry the leader's proposal, a reply whose
+        // attributes changed could not have been part of a fast quorum
+        if accept.is_none() && !replies.iter().any(|r| r.3 == ins.0) {
+            let proposed: Vec<&InstanceInfo<S::Command>> = replies
+                .iter()
+                .filter(|r| r.1 .1 == 0 && r.1 .2 == ins.0 && r.2)
+                .filter_map(|r| r.0.as_ref())
+                .filter(|info| info.3 == CommandState::PreAccepted)
+                .collect();
+            let needed = usize::from(Self::max_failures(n).div_ceil(2) + Self::extra(n));
+            if proposed.len() >= needed {
+                let info = proposed[0];
+                accept = Some((info.0.clone(), info.1, info.2.clone()));
+            }
+        }
+
+        if let Some((req, seq, deps)) = accept {
+            Self::update_state(
+                &mut rs,
+                req.clone(),
+                seq,
+                deps.clone(),
+                ins,
+                CommandState::Accepted,
+                replica_id,
+            );
+            rs.naccept.insert(ins, HashSet::new());
+            drop(rs);
+            return Some(Recovery::Accept(req, seq, deps));
+        }
+
+        // restart Phase 1 with the command if anyone has seen it, otherwise with a no-op; the
+        // replies may disagree on the attributes, start from the highest seq and every dependence
+        let (req, seq, deps) = match current.first() {
+            Some(info) => (
+                info.0.clone(),
+                current.iter().map(|info| info.1).max().unwrap(),
+                current
+                    .iter()
+                    .flat_map(|info| info.2.iter())
+                    .map(|d| (*d, false))
+                    .collect(),
+            ),
+            None => (ClientRequest::NoOp, 1, HashMap::new()),
+        };
+        let (seq, deps) = Self::attributes(&rs, &req, seq, deps, ins);
+        Self::update_state(
+            &mut rs,
+            req.clone(),
+            seq,
+            deps.keys().cloned().collect(),
+            ins,
+            CommandState::PreAccepted,
+            replica_id,
+        );
+        rs.preaccept_replies.insert(ins, Default::default());
+        rs.avoid_fast_path.insert(ins);
+        drop(rs);
+
+        Some(Recovery::PreAccept(req, seq, deps))
+    }
+
+    // writes executed since the last call, and the snapshot they start after if one was
+    // installed in between
+    pub fn atomic_take_trace(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+    ) -> (Vec<(Instance, String)>, Option<Coverage>) {
+        let mut rs = replica_state.lock().unwrap();
+        let writes = std::mem::take(&mut rs.trace);
+        let reset = rs.trace_reset.take();
+        drop(rs);
+        (writes, reset)
+    }
+
+    pub fn atomic_executed_prefix(replica_state: Arc<Mutex<ReplicaState<S>>>) -> HashMap<u8, u64> {
+        let rs = replica_state.lock().unwrap();
+        let prefix = rs.executed_prefix.clone();
+        drop(rs);
+        prefix
+    }
+
+    // executed prefix and state machine of the replica, None while it executed instances above
+    // its prefix; replicas with the same prefix have then executed the same instances
+    pub fn atomic_settled_state(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+    ) -> Option<SettledState> {
+        let rs = replica_state.lock().unwrap();
+        let settled = rs
+            .executed
+            .iter()
+            .all(|ins| ins.1 <= rs.executed_prefix.get(&ins.0).cloned().unwrap_or(0));
+        let state = settled.then(|| (rs.executed_prefix.clone(), rs.state.to_string()));
+        drop(rs);
+        state
+    }
+
+    // records the executed prefix of sender_id and removes every instance that all members have
+    // executed, returns the number of instances removed
+    pub fn atomic_checkpoint(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        sender_id: u8,
+        prefix: HashMap<u8, u64>,
+    ) -> usize {
+        let mut rs = replica_state.lock().unwrap();
+        rs.peer_prefixes.insert(sender_id, prefix);
+        let own = rs.executed_prefix.clone();
+        rs.peer_prefixes.insert(replica_id, own);
+
+        let mut removed = 0;
+        let mut members: Vec<u8> = rs.members.iter().cloned().collect();
+        members.sort();
+        for r in members.clone() {
+            // a replica we have not heard from yet has executed nothing
+            let stable = members
+                .iter()
+                .map(|id| {
+                    let prefix = rs.peer_prefixes.get(id);
+                    prefix.and_then(|p| p.get(&r)).cloned().unwrap_or(0)
+                })
+                .min()
+                .unwrap_or(0);
+            let from = rs.collected.get(&r).cloned().unwrap_or(0);
+            for i in from + 1..=stable {
+                Self::collect(&mut rs, (r, i));
+                removed += 1;
+            }
+            if stable > from {
+                Self::persist(&mut rs, &LogRecord::Collected(r, stable));
+                rs.collected.insert(r, stable);
+            }
+        }
+        // the records of the collected instances are no longer needed
+        if removed > 0 {
+            Self::compact_log(&mut rs);
+        }
+
+        drop(rs);
+        removed
+    }
+
+    fn collect(rs: &mut ReplicaState<S>, ins: Instance) {
+        rs.cmds.remove(&ins);
+        if let Some(node) = rs.node_index.remove(&ins) {
+            rs.dep_graph.remove_node(node);
+        }
+        rs.executed.remove(&ins);
+        rs.preaccept_replies.remove(&ins);
+        rs.naccept.remove(&ins);
+        rs.ballots.remove(&ins);
+        rs.recovering.remove(&ins);
+        rs.avoid_fast_path.remove(&ins);
+        rs.last_progress.remove(&ins);
+        rs.waiting.remove(&ins);
+    }
+
+    // number of instances the executed prefix of a peer is ahead of ours
+    pub fn atomic_lag(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        prefix: &HashMap<u8, u64>,
+    ) -> u64 {
+        let rs = replica_state.lock().unwrap();
+        let lag = prefix
+            .iter()
+            .map(|(r, p)| p.saturating_sub(rs.executed_prefix.get(r).cloned().unwrap_or(0)))
+            .sum();
+        drop(rs);
+        lag
+    }
+
+    pub fn atomic_is_empty(replica_state: Arc<Mutex<ReplicaState<S>>>) -> bool {
+        let rs = replica_state.lock().unwrap();
+        let empty = rs.executed_prefix.values().all(|p| *p == 0);
+        drop(rs);
+        empty
+    }
+
+    pub fn atomic_snapshot(replica_state: Arc<Mutex<ReplicaState<S>>>) -> Snapshot<S::Command> {
+        let rs = replica_state.lock().unwrap();
+        let above = |ins: &Instance| ins.1 > rs.executed_prefix.get(&ins.0).cloned().unwrap_or(0);
+        let snapshot = Snapshot {
+            state: rs.state.snapshot(),
+            prefix: rs.executed_prefix.clone(),
+            executed: rs
+                .executed
+                .iter()
+                .filter(|ins| above(ins))
+                .cloned()
+                .collect(),
+            committed: rs
+                .cmds
+                .iter()
+                .filter(|(ins, cmd)| above(ins) && cmd.3 == CommandState::Committed)
+                .map(|(ins, (req, seq, deps, _, leader, _))| {
+                    (*ins, req.clone(), *seq, deps.clone(), *leader)
+                })
+                .collect(),
+            configuration: (rs.members.clone(), rs.next_id, rs.epoch),
+            clients: rs.clients.clone(),
+        };
+        drop(rs);
+        snapshot
+    }
+
+    // installs snapshot if it is ahead of this replica, returns the committed instances that
+    // are left to execute
+    pub fn atomic_install_snapshot(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+        snapshot: Snapshot<S::Command>,
+    ) -> Option<Vec<Instance>> {
+        let mut rs = replica_state.lock().unwrap();
+        let ahead: u64 = snapshot.prefix.values().sum();
+        if ahead <= rs.executed_prefix.values().sum() {
+            drop(rs);
+            return None;
+        }
+
+        Self::persist(&mut rs, &LogRecord::Snapshot(snapshot.clone()));
+        Self::install_snapshot(replica_id, &mut rs, snapshot);
+        Self::compact_log(&mut rs);
+        let pending = rs
+            .cmds
+            .iter()
+            .filter(|(ins, cmd)| cmd.3 == CommandState::Committed && !rs.executed.contains(ins))
+            .map(|(ins, _)| *ins)
+            .collect();
+        drop(rs);
+        Some(pending)
+    }
+
+    fn install_snapshot(
+        replica_id: u8,
+        rs: &mut std::sync::MutexGuard<ReplicaState<S>>,
+        snapshot: Snapshot<S::Command>,
+    ) {
+        // the instances of the prefix are reflected in dict and gone from the state, like
+        // collected ones
+        for (r, p) in snapshot.prefix.iter() {
+            let from = rs.collected.get(r).cloned().unwrap_or(0);
+            for i in from + 1..=*p {
+                Self::collect(rs, (*r, i));
+            }
+            rs.collected.insert(*r, max(from, *p));
+            let highest = rs.highest_seen.get(r).cloned().unwrap_or(0);
+            rs.highest_seen.insert(*r, max(highest, *p));
+        }
+
+        // instances we executed that the snapshot did not will be executed again on top of it,
+        // and traced again
+        if rs.record_trace {
+            rs.trace.clear();
+            rs.trace_reset = Some((snapshot.prefix.clone(), snapshot.executed.clone()));
+        }
+        rs.state = S::restore(&snapshot.state);
+        (rs.members, rs.next_id, rs.epoch) = snapshot.configuration;
+        rs.clients = snapshot.clients;
+        rs.executed = snapshot.executed;
+        for (ins, req, seq, deps, leader) in snapshot.committed {
+            Self::update_state(rs, req, seq, deps, ins, CommandState::Committed, leader);
+        }
+        let mut executed_prefix = HashMap::new();
+        for (r, p) in rs.collected.iter() {
+            let mut prefix = *p;
+            while rs.executed.contains(&(*r, prefix + 1)) {
+                prefix += 1;
+            }
+            executed_prefix.insert(*r, prefix);
+        }
+        rs.executed_prefix = executed_prefix;
+        rs.waiting.clear();
+        Self::skip_used_instances(replica_id, rs);
+    }
+
+    // the next instance of this replica comes after every one of its instances that was
+    // collected or seen, one of those would be dropped as already taken
+    fn skip_used_instances(replica_id: u8, rs: &mut std::sync::MutexGuard<ReplicaState<S>>) {
+        let collected = rs.collected.get(&replica_id).cloned().unwrap_or(0);
+        let seen = rs.highest_seen.get(&replica_id).cloned().unwrap_or(0);
+        rs.instance_number = max(rs.instance_number, max(collected, seen));
+    }
+
+    // number of members of the current configuration and whether this replica is one of them
+    pub fn atomic_membership(
+        replica_id: u8,
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+    ) -> (u8, bool) {
+        let rs = replica_state.lock().unwrap();
+        let membership = (rs.members.len() as u8, rs.members.contains(&replica_id));
+        drop(rs);
+        membership
+    }
+
+    // replicas added and removed since the last call
+    pub fn atomic_membership_changes(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+    ) -> (Vec<SocketAddr>, Vec<u8>) {
+        let mut rs = replica_state.lock().unwrap();
+        let changes = (std::mem::take(&mut rs.joined), std::mem::take(&mut rs.left));
+        drop(rs);
+        changes
+    }
+
+    // replies to clients queued since the last call
+    pub fn atomic_take_replies(
+        replica_state: Arc<Mutex<ReplicaState<S>>>,
+    ) -> Vec<(ClientReply, SocketAddr)> {
+        let mut rs = replica_state.lock().unwrap();
+        let replies = std::mem::take(&mut rs.replies);
+        drop(rs);
+        replies
+    }
+
+    pub fn atomic_is_collected(replica_state: Arc<Mutex<ReplicaState<S>>>, ins: Instance) -> bool {
+        let rs = replica_state.lock().unwrap();
+        let collected = Self::is_collected(&rs, ins);
+        drop(rs);
+        collected
+    }
+
+    // sends message to peer, connecting to it first if it is new, e.g. a replica added to the
+    // configuration
+    pub async fn send_to<T: Transport>(
+        transport: &T,
+        connections: &mut HashMap<SocketAddr, T::Connection>,
+        peer: SocketAddr,
+        message: &Event<S::Command>,
+    ) {
+        let connection = match connections.entry(peer) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match transport.connect(peer).await {
+                Ok(connection) => {
+                    println!("Connected to new replica {}", peer);
+                    entry.insert(connection)
+                }
+                // a new replica may not listen yet, it is reached with a later message
+                Err(_) => return,
+            },
+        };
+        match message {
+            Event::Ping(_, _, _) => {
+                // a peer that restarted is reachable again once we reconnect to it
+                if transport.send(connection, message).await.is_err() {
+                    if let Ok(new_connection) = transport.connect(peer).await {
+                        println!("Reconnected to {}", peer);
+                        *connection = new_connection;
+                    }
+                }
+            }
+            _ => {
+                let _ = transport.send(connection, message).await;
+                println!("Sent {:?} to {}", message, peer);
+            }
+        }
+    }
+
+    // I/O layer of the replica: feeds every event to the protocol and carries out the actions it
+    // returns
+    pub async fn dispatch<T: Transport>(
+        mut protocol: Protocol<S>,
+        receiver: Receiver<Event<S::Command>>,
+        transport: T,
+        mut connections: HashMap<SocketAddr, T::Connection>,
     ) -> io::Result<()> {
         while let Ok(event) = receiver.recv().await {
-            // Process event and construct reply.
             match event {
                 // Testing network setup
                 Event::Message(addr, msg) => {
                     println!("{} says: {}", addr, msg);
 
-                    for (_, stream) in streams.iter_mut() {
-                        let _ = stream.write_all("Forward ".as_bytes()).await;
-                        let _ = stream.write_all(&msg.as_bytes()).await;
-                        let _ = stream.write_all("\n".as_bytes()).await;
-                        println!("Forwarded message to {}", stream.get_ref().peer_addr()?);
+                    let message: Event<S::Command> = Event::Forward(addr, msg);
+                    for (peer, connection) in connections.iter_mut() {
+                        let _ = transport.send(connection, &message).await;
+                        println!("Forwarded message to {}", peer);
                     }
                 }
                 Event::Forward(addr, msg) => {
                     println!("{} forwarded: {}", addr, msg);
 
-                    for (_, stream) in streams.iter_mut() {
-                        let _ = stream.write_all("Acknowle\n".as_bytes()).await;
+                    let message: Event<S::Command> = Event::Acknowledge(addr);
+                    for (_, connection) in connections.iter_mut() {
+                        let _ = transport.send(connection, &message).await;
                         println!("Acknowledged message from {}", addr);
                     }
                 }
-                Event::Ping(addr, _) => {
-                    println!("Pong back to {}", addr)
-                }
-                Event::SaveState => {
-                    let formatted_state = Replica::format_log(n, replica_state.clone());
-                    let mut file = File::create(format!("id_{}.txt", replica_id))?;
-                    file.write_all(formatted_state.as_bytes())?;
-                }
-
-                // EPaxos client request handling
-                Event::ReceivedRequest(req) => {
-                    let (seq, deps, ins) = Replica::atomic_request_preaccept(
-                        replica_id,
-                        replica_state.clone(),
-                        req.clone(),
-                        1,
-                        HashMap::new(),
-                        None,
-                        replica_id,
-                    );
-
-                    // Send PreAccept to all:
-                    for (_, stream) in streams.iter_mut() {
-                        let deps: CommittedDeps = CommittedDeps {
-                            committed: deps.clone(),
-                        };
-                        let message = Event::PreAccept(
-                            req.clone(),
-                            seq,
-                            deps.clone(),
-                            ins,
-                            replica_addr,
-                            replica_id,
-                        );
-
-                        let _ = stream
-                            .write_all(serde_json::to_string(&message).ok().unwrap().as_bytes())
-                            .await;
-                        let _ = stream.write_all("\n".as_bytes()).await;
-
-                        println!("Sent {:?} to {}", message, stream.get_ref().peer_addr()?);
-                    }
-                }
-                Event::PreAccept(req, cseq, cdeps, cins, leader_addr, leader_id) => {
-                    let (seq, deps, _) = Replica::atomic_request_preaccept(
-                        replica_id,
-                        replica_state.clone(),
-                        req.clone(),
-                        cseq,
-                        cdeps.committed,
-                        Some(cins),
-                        leader_id,
-                    );
-
-                    let stream = streams.get_mut(&leader_addr).unwrap();
-                    let deps: CommittedDeps = CommittedDeps {
-                        committed: deps.clone(),
-                    };
-                    let message = Event::PreAcceptOK(
-                        req.clone(),
-                        seq,
-                        deps.clone(),
-                        cins,
-                        replica_addr,
-                        leader_id,
-                    );
-
-                    let _ = stream
-                        .write_all(serde_json::to_string(&message).ok().unwrap().as_bytes())
-                        .await;
-                    let _ = stream.write_all("\n".as_bytes()).await;
-
-                    println!("Replied {:?} to {}", message, stream.get_ref().peer_addr()?);
-                }
-                Event::PreAcceptOK(req, cseq, cdeps, cins, _, leader_id) => {
-                    let path = Replica::path(
-                        n,
-                        replica_state.clone(),
-                        req.clone(),
-                        cseq,
-                        cdeps.committed,
-                        cins,
-                        leader_id,
-                    );
-                    match path {
-                        // either take fast or slow
-                        Some((seq, deps, take_fast)) => {
-                            if take_fast {
-                                Replica::execute_command(replica_state.clone(), replica_id);
-
-                                // notify other replicas about the commit
-                                for (_, stream) in streams.iter_mut() {
-                                    let message = Event::Commit(
-                                        req.clone(),
-                                        seq,
-                                        deps.clone(),
-                                        cins,
-                                        replica_addr,
-                                        leader_id,
-                                    );
-
-                                    let _ = stream
-                                        .write_all(
-                                            serde_json::to_string(&message)
-                                                .ok()
-                                                .unwrap()
-                                                .as_bytes(),
-                                        )
-                                        .await;
-                                    let _ = stream.write_all("\n".as_bytes()).await;
-
-                                    println!(
-                                        "Sent {:?} to {}",
-                                        message,
-                                        stream.get_ref().peer_addr()?
-                                    );
-                                }
-                            } else {
-                                // notify other replicas about the accept
-                                for (_, stream) in streams.iter_mut() {
-                                    let message = Event::Accept(
-                                        req.clone(),
-                                        seq,
-                                        deps.clone(),
-                                        cins,
-                                        replica_addr,
-                                        leader_id,
-                                    );
-
-                                    let _ = stream
-                                        .write_all(
-                                            serde_json::to_string(&message)
-                                                .ok()
-                                                .unwrap()
-                                                .as_bytes(),
-                                        )
-                                        .await;
-                                    let _ = stream.write_all("\n".as_bytes()).await;
-
-                                    println!(
-                                        "Sent {:?} to {}",
-                                        message,
-                                        stream.get_ref().peer_addr()?
-                                    );
-                                }
+                event => {
+                    for action in protocol.handle(event) {
+                        match action {
+                            Action::Send(peer, message) => {
+                                Self::send_to(&transport, &mut connections, peer, &message).await;
+                            }
+                            Action::Reply(reply, addr) => {
+                                let reply = Self::reply_to_client(transport.clone(), reply, addr);
+                                smol::spawn(reply).detach();
+                            }
+                            Action::Disconnect(peer) => {
+                                connections.remove(&peer);
+                            }
+                            Action::Save(path, contents) => {
+                                let mut file = File::create(path)?;
+                                file.write_all(contents.as_bytes())?;
+                            }
+                            Action::Halt(reason) => {
+                                eprintln!("{}", reason);
+                                exit(1);
                             }
-                        }
-                        // either not enough, late, or some error occurred (check log for error)
-                        None => (),
-                    }
-                }
-                Event::Commit(req, cseq, cdeps, cins, _, leader_id) => {
-                    Replica::atomic_update_state(
-                        replica_state.clone(),
-                        req,
-                        cseq,
-                        cdeps,
-                        cins,
-                        CommandState::Committed,
-                        leader_id,
-                    );
-                    println!("{}", Replica::format_log(n, replica_state.clone()));
-                    Replica::execute_command(replica_state.clone(), replica_id);
-                }
-                Event::Accept(req, cseq, cdeps, cins, leader_addr, leader_id) => {
-                    Replica::atomic_update_state(
-                        replica_state.clone(),
-                        req.clone(),
-                        cseq,
-                        cdeps.clone(),
-                        cins,
-                        CommandState::Accepted,
-                        leader_id,
-                    );
-
-                    // reply to leader
-                    let stream = streams.get_mut(&leader_addr).unwrap();
-                    let message = Event::AcceptOK(req, cseq, cdeps, cins, replica_addr, leader_id);
-
-                    let _ = stream
-                        .write_all(serde_json::to_string(&message).ok().unwrap().as_bytes())
-                        .await;
-                    let _ = stream.write_all("\n".as_bytes()).await;
-
-                    println!("Replied {:?} to {}", message, stream.get_ref().peer_addr()?);
-                }
-                Event::AcceptOK(req, cseq, cdeps, cins, _, leader_id) => {
-                    let commit = Replica::atomic_accept(
-                        n,
-                        replica_state.clone(),
-                        req.clone(),
-                        cseq,
-                        cdeps.clone(),
-                        cins,
-                        leader_id,
-                    );
-                    if commit {
-                        Replica::execute_command(replica_state.clone(), replica_id);
-
-                        // notify other replicas about the commit
-                        for (_, stream) in streams.iter_mut() {
-                            let message = Event::Commit(
-                                req.clone(),
-                                cseq,
-                                cdeps.clone(),
-                                cins,
-                                replica_addr,
-                                leader_id,
-                            );
-
-                            let _ = stream
-                                .write_all(serde_json::to_string(&message).ok().unwrap().as_bytes())
-                                .await;
-                            let _ = stream.write_all("\n".as_bytes()).await;
-
-                            println!("Sent {:?} to {}", message, stream.get_ref().peer_addr()?);
                         }
                     }
                 }
-                _ => (),
-            };
+            }
         }
         Ok(())
     }
 
-    /// Reads requests from the other party and forwards them to the dispatcher task.
-    async fn read_requests(sender: Sender<Event>, stream: Async<TcpStream>) -> io::Result<()> {
-        // read incoming lines until newlines
-        let mut lines = io::BufReader::new(stream).lines();
-
-        while let Some(line) = lines.next().await {
-            match line {
-                Ok(line) => {
-                    println!("Message received: {}", line);
+    /// Sends a local event to the dispatcher task every interval.
+    async fn timer(
+        sender: Sender<Event<S::Command>>,
+        interval: Duration,
+        event: fn() -> Event<S::Command>,
+    ) {
+        loop {
+            Timer::after(interval).await;
+            if sender.send(event()).await.is_err() {
+                break;
+            }
+        }
+    }
 
-                    // parse and forward to dispatch
-                    let json: Event = serde_json::from_str(line.as_str())?;
-                    sender.send(json).await.ok();
+    /// Reads requests from the other party and forwards them to the dispatcher task, through the
+    /// nemesis that injects faults into the messages of other replicas.
+    async fn read_requests<T: Transport>(
+        transport: T,
+        sender: Sender<Event<S::Command>>,
+        mut incoming: T::Incoming,
+        nemesis: NemesisConfig,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        let mut nemesis = Nemesis::new(nemesis, addr);
+
+        while let Some(message) = transport.receive(&mut incoming).await {
+            match message {
+                Ok(message) => {
+                    println!("Message received: {:?}", message);
+
+                    // forward to dispatch
+                    for (delay, event) in nemesis.inject(message) {
+                        if delay.is_zero() {
+                            sender.send(event).await.ok();
+                        } else {
+                            let sender = sender.clone();
+                            smol::spawn(async move {
+                                Timer::after(delay).await;
+                                sender.send(event).await.ok();
+                            })
+                            .detach();
+                        }
+                    }
+                    if let Some(flush) = nemesis.flush_held() {
+                        let sender = sender.clone();
+                        smol::spawn(async move {
+                            if let Some(event) = flush.await {
+                                sender.send(event).await.ok();
+                            }
+                        })
+                        .detach();
+                    }
+                }
+                // the frame of a message that cannot be decoded is skipped, a broken frame ends
+                // the connection
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    println!("Read_request Error: {}", e);
                 }
                 Err(e) => {
                     println!("Read_request Error: {}", e);
+                    return Err(e);
                 }
             }
         }
         Ok(())
     }
 
-    pub fn start(&mut self) -> io::Result<()> {
+    pub fn start<T: Transport>(&mut self, transport: T) -> io::Result<()> {
         smol::block_on(async {
+            // rebuild the state from the write-ahead log
+            if let Some(path) = self.config.wal.clone() {
+                let (wal, records) = Wal::open(&path, self.config.fsync)?;
+                println!("Replaying {} records of {}", records.len(), path);
+                let pending =
+                    Self::atomic_replay(self.id, self.replica_state.clone(), records, wal);
+                for ins in pending {
+                    Self::execute_command(self.replica_state.clone(), self.id, ins);
+                }
+            }
+
             // listen incoming connections
-            let listener = Async::<TcpListener>::bind(self.listen_addr)?;
-            println!(
-                "Listening to connections on {}",
-                listener.get_ref().local_addr()?
-            );
+            let listener = transport.bind(self.listen_addr).await?;
+            println!("Listening to connections on {}", self.listen_addr);
 
-            let mut streams: HashMap<SocketAddr, Async<TcpStream>> = HashMap::new();
-            // establish tcp connection with other replicas
+            let mut connections: HashMap<SocketAddr, T::Connection> = HashMap::new();
+            // establish a connection with the other replicas
             for addr in self.connections.iter() {
                 let mut waiting: bool = false;
 
                 // keep trying until connection succeeds
                 loop {
-                    match Async::<TcpStream>::connect(*addr).await {
-                        Ok(stream) => {
+                    match transport.connect(*addr).await {
+                        Ok(connection) => {
                             // Intro messages.
-                            println!("Connected to {}", stream.get_ref().peer_addr()?);
-                            println!("My nickname: {}", stream.get_ref().local_addr()?);
+                            println!("Connected to {}", addr);
 
-                            streams.insert(*addr, stream);
+                            connections.insert(*addr, connection);
 
                             break;
                         }
@@ -1025,34 +2377,690 @@ impl Replica {
             }
 
             let (sender, receiver) = unbounded();
-            smol::spawn(Replica::dispatch(
-                self.id,
-                self.addr,
-                self.n,
-                self.replica_state.clone(),
-                receiver,
-                streams,
+
+            // periodically look for instances whose command leader may have failed
+            smol::spawn(Self::timer(
+                sender.clone(),
+                self.config.recovery_timeout / 2,
+                || Event::CheckTimeouts,
+            ))
+            .detach();
+
+            // periodically ping every peer
+            smol::spawn(Self::timer(
+                sender.clone(),
+                self.config.heartbeat_interval,
+                || Event::Heartbeat,
             ))
             .detach();
 
+            // periodically compare execution order with the other replicas
+            if self.config.check_order {
+                smol::spawn(Self::timer(sender.clone(), Duration::from_secs(1), || {
+                    Event::ShareTrace
+                }))
+                .detach();
+            }
+
+            // periodically force the write-ahead log to disk
+            if self.config.wal.is_some() && self.config.fsync == FsyncPolicy::Grouped {
+                smol::spawn(Self::timer(
+                    sender.clone(),
+                    self.config.fsync_interval,
+                    || Event::SyncLog,
+                ))
+                .detach();
+            }
+
+            // periodically checkpoint and collect the instances every replica executed
+            smol::spawn(Self::timer(
+                sender.clone(),
+                self.config.checkpoint_interval,
+                || Event::TakeCheckpoint,
+            ))
+            .detach();
+
+            // periodically fall back to all replicas for thrifty PreAccepts without a fast quorum
+            if self.config.thrifty {
+                smol::spawn(Self::timer(
+                    sender.clone(),
+                    self.config.thrifty_timeout / 2,
+                    || Event::CheckThrifty,
+                ))
+                .detach();
+            }
+
+            let dispatch =
+                Self::dispatch(self.protocol(), receiver, transport.clone(), connections);
+            smol::spawn(dispatch).detach();
+
+            // the partition schedule starts with the replica unless the cluster shares a start
+            let mut nemesis = self.config.nemesis.clone();
+            if nemesis.start.is_none() {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                nemesis.start = Some(now.as_millis() as u64);
+            }
+
             loop {
                 // Accept the next connection.
-                let (stream, _) = listener.accept().await?;
-                println!(
-                    "{} can now send messages to {}",
-                    stream.get_ref().peer_addr()?,
-                    stream.get_ref().local_addr()?
-                );
+                let incoming = transport.accept(&listener).await?;
+                println!("A new connection can now send messages to {}", self.addr);
 
+                let transport = transport.clone();
                 let sender = sender.clone();
+                let nemesis = nemesis.clone();
+                let addr = self.addr;
 
                 // Spawn a background task reading messages from the other party.
                 smol::spawn(async move {
                     // Read messages from the other party and ignore I/O errors when the other party quits.
-                    Replica::read_requests(sender.clone(), stream).await.ok();
+                    Self::read_requests(transport, sender.clone(), incoming, nemesis, addr)
+                        .await
+                        .ok();
                 })
                 .detach();
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::kv::{KvCommand, KvStore};
+
+    #[test]
+    fn new_instances_come_after_an_installed_snapshot() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut snapshot = Replica::atomic_snapshot(state.clone());
+        snapshot.prefix = HashMap::from([(1, 5), (2, 3)]);
+        assert!(Replica::atomic_install_snapshot(1, state.clone(), snapshot).is_some());
+
+        // instance (1, 1) was collected with the prefix, a PreAccept for it would be dropped
+        let cmd = KvCommand::Write("a".to_string(), "1".to_string());
+        let req = ClientRequest::Command(cmd, addr, (1, 0, 0));
+        let (_, _, ins) =
+            Replica::atomic_request_preaccept(1, state, req, 0, HashMap::new(), None, 1);
+        assert_eq!(ins, (1, 6));
+    }
+
+    // replica 1 of 5 recovers (2, 1) at ballot (0, 1, 1) and receives the PrepareOKs of
+    // replicas 3, 4 and 5, a majority with its own; returns the outcome and the state it left
+    fn recover(
+        replies: Vec<AcceptedInfo<KvCommand>>,
+    ) -> (
+        Option<Recovery<KvCommand>>,
+        Arc<Mutex<ReplicaState<KvStore>>>,
+    ) {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 5, Default::default());
+        let state = replica.replica_state();
+        let (ins, ballot) = ((2, 1), (0, 1, 1));
+        let own = (None, (0, 0, 2), false);
+        state
+            .lock()
+            .unwrap()
+            .recovering
+            .insert(ins, (ballot, vec![(own.0, own.1, own.2, 1)]));
+        let mut outcome = None;
+        for (reply, sender_id) in replies.into_iter().zip(3..) {
+            assert!(outcome.is_none());
+            outcome =
+                Replica::atomic_prepare_ok(5, 1, state.clone(), ballot, ins, reply, sender_id);
+        }
+        (outcome, state)
+    }
+
+    fn write_a() -> ClientRequest<KvCommand> {
+        let addr = SocketAddr::from(([10, 0, 1, 1], 8000));
+        ClientRequest::Command(
+            KvCommand::Write("a".to_string(), "1".to_string()),
+            addr,
+            (1, 0, 0),
+        )
+    }
+
+    fn seen(
+        seq: SeqNumber,
+        deps: &[Instance],
+        state: CommandState,
+    ) -> Option<InstanceInfo<KvCommand>> {
+        Some((write_a(), seq, deps.iter().cloned().collect(), state))
+    }
+
+    #[test]
+    fn recovery_keeps_a_committed_reply() {
+        let (outcome, state) = recover(vec![
+            (seen(2, &[], CommandState::PreAccepted), (0, 0, 2), true),
+            (
+                seen(5, &[(3, 1)], CommandState::Committed),
+                (0, 0, 2),
+                false,
+            ),
+        ]);
+        match outcome {
+            Some(Recovery::Commit(req, 5, deps)) => {
+                assert_eq!(req, write_a());
+                assert_eq!(deps, HashSet::from([(3, 1)]));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            state.lock().unwrap().cmds[&(2, 1)].3,
+            CommandState::Committed
+        );
+    }
+
+    #[test]
+    fn recovery_accepts_the_reply_with_the_highest_ballot() {
+        let (outcome, _) = recover(vec![
+            (seen(3, &[], CommandState::Accepted), (0, 0, 2), false),
+            (seen(4, &[(3, 1)], CommandState::Accepted), (0, 1, 3), false),
+        ]);
+        match outcome {
+            Some(Recovery::Accept(_, 4, deps)) => assert_eq!(deps, HashSet::from([(3, 1)])),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn recovery_accepts_preaccepts_that_kept_the_proposal() {
+        // floor((F + 1) / 2) = 1 reply is enough with 5 replicas
+        let (outcome, state) = recover(vec![
+            (
+                seen(2, &[(1, 1)], CommandState::PreAccepted),
+                (0, 0, 2),
+                true,
+            ),
+            (None, (0, 0, 2), false),
+        ]);
+        match outcome {
+            Some(Recovery::Accept(_, 2, deps)) => assert_eq!(deps, HashSet::from([(1, 1)])),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            state.lock().unwrap().cmds[&(2, 1)].3,
+            CommandState::Accepted
+        );
+    }
+
+    #[test]
+    fn recovery_restarts_from_disagreeing_preaccepts() {
+        let (outcome, state) = recover(vec![
+            (
+                seen(2, &[(1, 1)], CommandState::PreAccepted),
+                (0, 0, 2),
+                false,
+            ),
+            (
+                seen(3, &[(3, 1)], CommandState::PreAccepted),
+                (0, 0, 2),
+                false,
+            ),
+        ]);
+        match outcome {
+            Some(Recovery::PreAccept(req, 3, deps)) => {
+                assert_eq!(req, write_a());
+                assert_eq!(deps, HashMap::from([((1, 1), false), ((3, 1), false)]));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(state.lock().unwrap().avoid_fast_path.contains(&(2, 1)));
+    }
+
+    #[test]
+    fn recovery_proposes_a_noop_when_no_reply_knows_the_command() {
+        let (outcome, _) = recover(vec![(None, (0, 0, 2), false), (None, (0, 0, 2), false)]);
+        match outcome {
+            Some(Recovery::PreAccept(ClientRequest::NoOp, 1, deps)) => assert!(deps.is_empty()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // commits req at ins and executes it, returns the result and request number replied
+    fn commit_and_apply(
+        rs: &mut std::sync::MutexGuard<ReplicaState<KvStore>>,
+        ins: Instance,
+        req: ClientRequest<KvCommand>,
+    ) -> Option<(Option<String>, u64)> {
+        Replica::update_state(
+            rs,
+            req,
+            1,
+            HashSet::new(),
+            ins,
+            CommandState::Committed,
+            ins.0,
+        );
+        Replica::apply(rs, ins).map(|(ClientReply::Reply(res, number), _, _)| (res, number))
+    }
+
+    #[test]
+    fn retried_requests_are_executed_once() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut rs = state.lock().unwrap();
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let append = |suffix: &str, id| {
+            let cmd = KvCommand::Append("a".to_string(), suffix.to_string());
+            ClientRequest::Command(cmd, client, id)
+        };
+
+        assert_eq!(
+            commit_and_apply(&mut rs, (1, 1), append("x", (7, 3, 3))),
+            Some((None, 3))
+        );
+        // the client timed out and sent request 3 again through replica 2
+        assert_eq!(
+            commit_and_apply(&mut rs, (2, 1), append("x", (7, 3, 3))),
+            Some((None, 3))
+        );
+        assert_eq!(rs.state.to_string(), r#"[("a", "x")]"#);
+
+        // request 4 acknowledges the reply of 3, which is dropped from the session
+        assert_eq!(
+            commit_and_apply(&mut rs, (1, 2), append("y", (7, 4, 4))),
+            Some((Some("x".to_string()), 4))
+        );
+        assert!(!rs.clients[&7].1.contains_key(&3));
+        // a late copy of request 3 is neither executed nor answered
+        assert_eq!(
+            commit_and_apply(&mut rs, (3, 1), append("x", (7, 3, 3))),
+            None
+        );
+        assert_eq!(rs.state.to_string(), r#"[("a", "xy")]"#);
+        assert!(rs.executed.contains(&(3, 1)));
+    }
+
+    #[test]
+    fn indexed_attributes_cover_every_interfering_instance() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut rs = state.lock().unwrap();
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let interfere = |a: &ClientRequest<KvCommand>, b: &ClientRequest<KvCommand>| {
+            let shared = a.keys().iter().any(|key| b.keys().contains(key));
+            let both = |access| a.access() == access && b.access() == access;
+            shared && !both(Access::Read) && !both(Access::Commutative)
+        };
+
+        // reads, writes and increments of shared keys a and b and of a key per replica, with
+        // some two-key transactions, from every replica and in every state
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut numbers: HashMap<u8, u64> = HashMap::new();
+        let mut log: Vec<(Instance, ClientRequest<KvCommand>, SeqNumber)> = Vec::new();
+        for c in 0..300 {
+            let leader = rng.gen_range(1..=3);
+            let key = ["a", "b", &format!("own{}", leader)][rng.gen_range(0..3)].to_string();
+            let cmd = match rng.gen_range(0..10) {
+                0..=2 => KvCommand::Read(key),
+                3..=5 => KvCommand::Increment(key, 1),
+                6..=8 => KvCommand::Write(key, c.to_string()),
+                _ => KvCommand::Transaction(vec![
+                    KvCommand::Write(key, c.to_string()),
+                    KvCommand::Increment("b".to_string(), 1),
+                ]),
+            };
+            let req = ClientRequest::Command(cmd, client, (c, 0, 0));
+            let number = numbers.entry(leader).or_default();
+            *number += 1;
+            let ins = (leader, *number);
+
+            let (seq, deps) = Replica::attributes(&rs, &req, 0, HashMap::new(), ins);
+            let interfering: Vec<&(Instance, ClientRequest<KvCommand>, SeqNumber)> = log
+                .iter()
+                .filter(|(_, other, _)| interfere(&req, other))
+                .collect();
+            let scanned = interfering.iter().map(|(_, _, s)| s + 1).max().unwrap_or(0);
+            assert!(seq >= scanned, "seq of {:?}", ins);
+
+            // the direct dependencies interfere or come earlier from the same leader
+            for d in deps.keys() {
+                let (_, other, _) = log.iter().find(|(i, _, _)| i == d).unwrap();
+                assert!(
+                    interfere(&req, other) || d.0 == ins.0,
+                    "{:?} -> {:?}",
+                    ins,
+                    d
+                );
+            }
+            // and every interfering instance is reached through them
+            let mut reached: HashSet<Instance> = HashSet::new();
+            let mut stack: Vec<Instance> = deps.keys().cloned().collect();
+            while let Some(d) = stack.pop() {
+                if reached.insert(d) {
+                    stack.extend(rs.cmds[&d].2.iter().cloned());
+                }
+            }
+            for (i, _, _) in interfering {
+                assert!(reached.contains(i), "{:?} misses {:?}", ins, i);
+            }
+
+            let cmd_state = match rng.gen_range(0..3) {
+                0 => CommandState::PreAccepted,
+                1 => CommandState::Accepted,
+                _ => CommandState::Committed,
+            };
+            let deps: HashSet<Instance> = deps.keys().cloned().collect();
+            Replica::update_state(&mut rs, req.clone(), seq, deps, ins, cmd_state, leader);
+            log.push((ins, req, seq));
+        }
+    }
+
+    fn append(suffix: &str) -> ClientRequest<KvCommand> {
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let cmd = KvCommand::Append("a".to_string(), suffix.to_string());
+        ClientRequest::Command(cmd, client, (suffix.len() as u64, 0, 0))
+    }
+
+    #[test]
+    fn execution_waits_for_an_uncommitted_dependency() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut rs = state.lock().unwrap();
+        let deps = HashSet::from([(2, 1)]);
+        Replica::update_state(
+            &mut rs,
+            append("x"),
+            2,
+            deps,
+            (1, 1),
+            CommandState::Committed,
+            1,
+        );
+        Replica::update_state(
+            &mut rs,
+            append("yy"),
+            1,
+            HashSet::new(),
+            (2, 1),
+            CommandState::PreAccepted,
+            2,
+        );
+        drop(rs);
+
+        Replica::execute_command(state.clone(), 1, (1, 1));
+        let mut rs = state.lock().unwrap();
+        assert!(rs.executed.is_empty());
+        assert_eq!(rs.waiting[&(2, 1)], HashSet::from([(1, 1)]));
+
+        Replica::update_state(
+            &mut rs,
+            append("yy"),
+            1,
+            HashSet::new(),
+            (2, 1),
+            CommandState::Committed,
+            2,
+        );
+        drop(rs);
+        Replica::execute_command(state.clone(), 1, (2, 1));
+        let rs = state.lock().unwrap();
+        assert_eq!(rs.executed, HashSet::from([(1, 1), (2, 1)]));
+        assert!(rs.waiting.is_empty());
+        assert_eq!(rs.state.to_string(), r#"[("a", "yyx")]"#);
+    }
+
+    #[test]
+    fn a_cycle_executes_in_seq_then_instance_order() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut rs = state.lock().unwrap();
+        // (1, 1) -> (2, 1) -> (3, 1) -> (1, 1), and (1, 2) after the cycle
+        let commit =
+            |rs: &mut std::sync::MutexGuard<ReplicaState<KvStore>>, ins, suffix, seq, dep| {
+                let deps = HashSet::from([dep]);
+                Replica::update_state(
+                    rs,
+                    append(suffix),
+                    seq,
+                    deps,
+                    ins,
+                    CommandState::Committed,
+                    ins.0,
+                );
+            };
+        commit(&mut rs, (1, 1), "a", 3, (2, 1));
+        commit(&mut rs, (3, 1), "cc", 2, (1, 1));
+        commit(&mut rs, (2, 1), "bbb", 2, (3, 1));
+        commit(&mut rs, (1, 2), "dddd", 1, (1, 1));
+        drop(rs);
+
+        Replica::execute_command(state.clone(), 1, (1, 2));
+        let rs = state.lock().unwrap();
+        assert_eq!(rs.executed.len(), 4);
+        assert_eq!(rs.state.to_string(), r#"[("a", "bbbccadddd")]"#);
+    }
+
+    #[test]
+    fn instances_are_collected_once_every_replica_executed_them() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let mut rs = state.lock().unwrap();
+        Replica::update_state(
+            &mut rs,
+            append("x"),
+            1,
+            HashSet::new(),
+            (1, 1),
+            CommandState::Committed,
+            1,
+        );
+        let deps = HashSet::from([(1, 1)]);
+        Replica::update_state(
+            &mut rs,
+            append("yy"),
+            2,
+            deps,
+            (1, 2),
+            CommandState::Committed,
+            1,
+        );
+        drop(rs);
+        Replica::execute_command(state.clone(), 1, (1, 2));
+
+        // replica 3 has not reported its prefix yet
+        let executed = HashMap::from([(1, 2)]);
+        assert_eq!(Replica::atomic_checkpoint(1, state.clone(), 2, executed), 0);
+        assert_eq!(
+            Replica::atomic_checkpoint(1, state.clone(), 3, HashMap::from([(1, 1)])),
+            1
+        );
+        let mut rs = state.lock().unwrap();
+        assert!(!rs.cmds.contains_key(&(1, 1)));
+        assert!(rs.cmds.contains_key(&(1, 2)));
+        assert!(Replica::is_executed(&rs, (1, 1)));
+
+        // a late PreAccept or Commit of the collected instance leaves no trace
+        Replica::update_state(
+            &mut rs,
+            append("x"),
+            1,
+            HashSet::new(),
+            (1, 1),
+            CommandState::PreAccepted,
+            1,
+        );
+        Replica::update_state(
+            &mut rs,
+            append("x"),
+            1,
+            HashSet::new(),
+            (1, 1),
+            CommandState::Committed,
+            1,
+        );
+        assert!(!rs.cmds.contains_key(&(1, 1)));
+        assert!(!rs.node_index.contains_key(&(1, 1)));
+        drop(rs);
+        Replica::execute_command(state.clone(), 1, (1, 1));
+        let rs = state.lock().unwrap();
+        assert_eq!(rs.state.to_string(), r#"[("a", "xyy")]"#);
+    }
+
+    #[test]
+    fn reconfigurations_allocate_ids_and_resize_the_quorums() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let new_addr = |i| SocketAddr::from(([10, 0, 0, i], 7000));
+        let run = |ins, req| {
+            let mut rs = state.lock().unwrap();
+            let reply = commit_and_apply(&mut rs, ins, req).and_then(|(res, _)| res);
+            drop(rs);
+            let (n, member) = Replica::atomic_membership(1, state.clone());
+            let quorums = (
+                Replica::<KvStore>::fast_quorum(n),
+                Replica::<KvStore>::slow_quorum(n),
+            );
+            (reply, n, member, quorums)
+        };
+
+        let add = |i, number| ClientRequest::AddReplica(new_addr(i), client, (1, number, number));
+        assert_eq!(
+            run((1, 1), add(4, 1)),
+            (Some("4".to_string()), 4, true, (3, 3))
+        );
+        assert_eq!(
+            run((2, 1), add(5, 2)),
+            (Some("5".to_string()), 5, true, (3, 3))
+        );
+        // IDs are never reused, even once their replica has left
+        let remove = |id, number| ClientRequest::RemoveReplica(id, client, (1, number, number));
+        assert_eq!(
+            run((3, 1), remove(4, 3)),
+            (Some("4".to_string()), 4, true, (3, 3))
+        );
+        assert_eq!(run((1, 2), remove(4, 4)), (None, 4, true, (3, 3)));
+        assert_eq!(
+            run((1, 3), add(6, 5)),
+            (Some("6".to_string()), 5, true, (3, 3))
+        );
+        assert_eq!(
+            run((2, 2), remove(1, 6)),
+            (Some("1".to_string()), 4, false, (3, 3))
+        );
+
+        let rs = state.lock().unwrap();
+        assert_eq!(rs.members, HashSet::from([2, 3, 5, 6]));
+        assert_eq!(rs.epoch, 5);
+    }
+
+    #[test]
+    fn leaders_of_an_older_epoch_keep_their_initial_ballot() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let replica = Replica::<KvStore>::new(1, addr, addr, Vec::new(), 3, Default::default());
+        let state = replica.replica_state();
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let new_addr = SocketAddr::from(([10, 0, 0, 4], 7000));
+        let mut rs = state.lock().unwrap();
+        let add = ClientRequest::AddReplica(new_addr, client, (1, 1, 1));
+        commit_and_apply(&mut rs, (1, 1), add);
+        assert_eq!(rs.epoch, 1);
+        drop(rs);
+
+        // replica 2 has not executed the AddReplica yet and still proposes in epoch 0
+        assert_eq!(
+            Replica::atomic_check_ballot(state.clone(), (0, 0, 2), (2, 1)),
+            Ok(())
+        );
+        assert_eq!(Replica::atomic_ballot(state.clone(), (2, 1)), (0, 0, 2));
+        // while new instances of this replica start in epoch 1
+        let (_, _, ins) = Replica::atomic_request_preaccept(
+            1,
+            state.clone(),
+            append("x"),
+            0,
+            HashMap::new(),
+            None,
+            1,
+        );
+        assert_eq!(Replica::atomic_ballot(state, ins), (1, 0, 1));
+    }
+
+    #[test]
+    fn replicas_outside_of_the_configuration_do_not_recover() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+        let peers = vec![
+            SocketAddr::from(([10, 0, 0, 2], 7000)),
+            SocketAddr::from(([10, 0, 0, 3], 7000)),
+        ];
+        let clock = Clock::new_virtual();
+        let config = ReplicaConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let timeout = config.recovery_timeout;
+        let replica = Replica::<KvStore>::new(1, addr, addr, peers, 3, config);
+        let state = replica.replica_state();
+        let mut protocol = replica.protocol();
+        let prepares = |actions: Vec<Action<KvCommand>>| {
+            actions
+                .iter()
+                .filter(|action| matches!(action, Action::Send(_, Event::Prepare(..))))
+                .count()
+        };
+
+        // (2, 1) has made no progress for twice the timeout of a live leader
+        state
+            .lock()
+            .unwrap()
+            .last_progress
+            .insert((2, 1), clock.now());
+        clock.advance_to(timeout * 2);
+        assert_eq!(prepares(protocol.handle(Event::CheckTimeouts)), 2);
+
+        let client = SocketAddr::from(([10, 0, 1, 1], 8000));
+        let mut rs = state.lock().unwrap();
+        commit_and_apply(
+            &mut rs,
+            (3, 1),
+            ClientRequest::RemoveReplica(1, client, (1, 1, 1)),
+        );
+        drop(rs);
+        clock.advance_to(timeout * 4);
+        assert_eq!(prepares(protocol.handle(Event::CheckTimeouts)), 0);
+    }
+
+    #[test]
+    fn quorum_sizes() {
+        // (n, F, fast quorum, slow quorum)
+        let table = [
+            (1, 0, 1, 1),
+            (2, 0, 2, 2),
+            (3, 1, 2, 2),
+            (4, 1, 3, 3),
+            (5, 2, 3, 3),
+            (6, 2, 4, 4),
+            (7, 3, 5, 4),
+            (8, 3, 6, 5),
+            (9, 4, 6, 5),
+        ];
+        for (n, f, fast, slow) in table {
+            assert_eq!(Replica::<KvStore>::max_failures(n), f, "F of {}", n);
+            assert_eq!(
+                Replica::<KvStore>::fast_quorum(n),
+                fast,
+                "fast quorum of {}",
+                n
+            );
+            assert_eq!(
+                Replica::<KvStore>::slow_quorum(n),
+                slow,
+                "slow quorum of {}",
+                n
+            );
+        }
+    }
+}
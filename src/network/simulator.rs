@@ -0,0 +1,691 @@
+use crate::network::clock::Clock;
+use crate::network::history::{check_linearizable, HistoryEvent};
+use crate::network::kv::{KvCommand, KvStore};
+use crate::network::nemesis::Partition;
+use crate::network::protocol::{Action, Protocol};
+use crate::network::replica::{
+    ClientReply, ClientRequest, Event, Instance, Replica, ReplicaConfig, ReplicaState, SeqNumber,
+    SettledState,
+};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// network and workload of a simulation
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    // seed of every random choice, the same seed replays the same run
+    pub seed: u64,
+    pub n: u8,
+    pub clients: u16,
+    // virtual time during which the clients send requests, the replicas then get as long again
+    // to settle
+    pub duration: Duration,
+    // a message takes between these two latencies to arrive, uniformly
+    pub latency: (Duration, Duration),
+    // probability that a message is lost
+    pub drop: f64,
+    // probability that a message overtakes the messages sent before it on the same link
+    pub reorder: f64,
+    // probability that a message between replicas is delivered twice
+    pub duplicate: f64,
+    // fraction of the requests on the shared key, of the requests that are increments and of
+    // the requests that are reads
+    pub conflict: f64,
+    pub increments: f64,
+    pub reads: f64,
+    // time between a reply and the next request of a client
+    pub think_time: Duration,
+    // time a client waits for a reply before sending the request to the next replica
+    pub request_timeout: Duration,
+    // partitions between the replicas, in virtual seconds; replica i is at 10.0.0.i:7000
+    pub partitions: Vec<Partition>,
+}
+
+// something that happens at a point of virtual time
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum SimEvent {
+    // Step(replica index, event): the replica handles a message or one of its timers
+    Step(usize, Event<KvCommand>),
+    // Request(client index): the client sends its next request
+    Request(usize),
+    // Reply(client index, reply)
+    Reply(usize, ClientReply),
+    // Timeout(client index, request number)
+    Timeout(usize, u64),
+}
+
+// client of a simulation, it sends one request at a time
+struct SimClient {
+    addr: SocketAddr,
+    // request waiting for its reply and the replica it was last sent to
+    pending: Option<(ClientRequest<KvCommand>, usize)>,
+    next_number: u64,
+    // request number -> reply
+    replies: HashMap<u64, Option<String>>,
+}
+
+// Runs n replicas and their clients in one process on a virtual clock: messages are delivered
+// in an order drawn from the seed, so that a run that breaks an invariant can be replayed.
+pub struct Simulation {
+    config: SimConfig,
+    replica_config: ReplicaConfig,
+    rng: StdRng,
+    clock: Clock,
+    now: Duration,
+    // events by time, ties broken by the order they were scheduled in
+    queue: BTreeMap<(Duration, u64), SimEvent>,
+    scheduled: u64,
+    addrs: Vec<SocketAddr>,
+    protocols: Vec<Protocol<KvStore>>,
+    states: Vec<Arc<Mutex<ReplicaState<KvStore>>>>,
+    // key -> writes in execution order, of each replica since its last snapshot install, as
+    // taken from its trace
+    traces: Vec<HashMap<String, Vec<Instance>>>,
+    clients: Vec<SimClient>,
+    // (sender, receiver) -> delivery time of the last message on the link, messages are
+    // delivered in order unless reordered
+    links: HashMap<(SocketAddr, SocketAddr), Duration>,
+    // instance -> command, seq and deps it was first committed with
+    committed: HashMap<Instance, (ClientRequest<KvCommand>, SeqNumber, HashSet<Instance>)>,
+    // invocation and completion of every client request, in virtual microseconds
+    history: Vec<HistoryEvent>,
+    sent: u64,
+    dropped: u64,
+}
+
+// order of the actions of one step, which the protocol may return in any order
+fn action_order(action: &Action<KvCommand>) -> (u8, Option<SocketAddr>, Option<Instance>) {
+    match action {
+        Action::Send(peer, message) => (0, Some(*peer), message.instance()),
+        Action::Reply(ClientReply::Reply(_, number), addr) => (1, Some(*addr), Some((0, *number))),
+        _ => (2, None, None),
+    }
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig, replica_config: ReplicaConfig) -> Self {
+        let clock = Clock::new_virtual();
+        let replica_config = ReplicaConfig {
+            wal: None,
+            join: false,
+            log_state: false,
+            clock: clock.clone(),
+            ..replica_config
+        };
+
+        let addrs: Vec<SocketAddr> = (1..=config.n)
+            .map(|id| SocketAddr::from(([10, 0, 0, id], 7000)))
+            .collect();
+        let mut protocols = Vec::new();
+        let mut states = Vec::new();
+        for (i, addr) in addrs.iter().enumerate() {
+            let peers = addrs.iter().filter(|a| *a != addr).cloned().collect();
+            // writes are always traced for check_order, ShareTrace only runs if asked for
+            let replica = Replica::<KvStore>::new(
+                i as u8 + 1,
+                *addr,
+                *addr,
+                peers,
+                config.n,
+                ReplicaConfig {
+                    check_order: true,
+                    ..replica_config.clone()
+                },
+            );
+            protocols.push(replica.protocol());
+            states.push(replica.replica_state());
+        }
+        let clients = (0..config.clients)
+            .map(|c| SimClient {
+                addr: SocketAddr::from(([10, 0, 1, 1], 8000 + c)),
+                pending: None,
+                next_number: 0,
+                replies: HashMap::new(),
+            })
+            .collect();
+
+        Simulation {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            replica_config,
+            clock,
+            now: Duration::ZERO,
+            queue: BTreeMap::new(),
+            scheduled: 0,
+            addrs,
+            protocols,
+            traces: vec![HashMap::new(); states.len()],
+            states,
+            clients,
+            links: HashMap::new(),
+            committed: HashMap::new(),
+            history: Vec::new(),
+            sent: 0,
+            dropped: 0,
+        }
+    }
+
+    fn schedule(&mut self, at: Duration, event: SimEvent) {
+        self.queue.insert((at, self.scheduled), event);
+        self.scheduled += 1;
+    }
+
+    // period of the local timer that produces event, None for a message
+    fn period(&self, event: &Event<KvCommand>) -> Option<Duration> {
+        let config = &self.replica_config;
+        match event {
+            Event::Heartbeat => Some(config.heartbeat_interval),
+            Event::CheckTimeouts => Some(config.recovery_timeout / 2),
+            Event::TakeCheckpoint => Some(config.checkpoint_interval),
+            Event::CheckThrifty => Some(config.thrifty_timeout / 2),
+            Event::ShareTrace => Some(Duration::from_secs(1)),
+            _ => None,
+        }
+    }
+
+    // time a message from one address to another is delivered at, None if it is lost
+    fn transmit(&mut self, from: SocketAddr, to: SocketAddr) -> Option<Duration> {
+        self.sent += 1;
+        let elapsed = self.now.as_secs_f64();
+        let partitioned = self
+            .config
+            .partitions
+            .iter()
+            .any(|p| p.separates(elapsed, from, to));
+        if partitioned && self.addrs.contains(&from) && self.addrs.contains(&to) {
+            self.dropped += 1;
+            return None;
+        }
+        if self.rng.gen::<f64>() < self.config.drop {
+            self.dropped += 1;
+            return None;
+        }
+        let (min, max) = self.config.latency;
+        let mut at = self.now + self.rng.gen_range(min..=max);
+        let reorder = self.rng.gen::<f64>() < self.config.reorder;
+        let last = self.links.entry((from, to)).or_default();
+        if !reorder {
+            at = at.max(*last);
+        }
+        *last = (*last).max(at);
+        Some(at)
+    }
+
+    // every replica commits the same command, seq and deps for an instance
+    fn check_commit(
+        &mut self,
+        ins: Instance,
+        req: &ClientRequest<KvCommand>,
+        seq: SeqNumber,
+        deps: &HashSet<Instance>,
+    ) -> Result<(), String> {
+        match self.committed.get(&ins) {
+            Some((r, s, d)) if (r, *s, d) != (req, seq, deps) => Err(format!(
+                "instance {:?} committed as {:?} {} {:?} and as {:?} {} {:?}",
+                ins, r, s, d, req, seq, deps
+            )),
+            Some(_) => Ok(()),
+            None => {
+                self.committed.insert(ins, (req.clone(), seq, deps.clone()));
+                Ok(())
+            }
+        }
+    }
+
+    fn step(&mut self, i: usize, event: Event<KvCommand>) -> Result<(), String> {
+        if let Some(period) = self.period(&event) {
+            self.schedule(self.now + period, SimEvent::Step(i, event.clone()));
+        }
+
+        let mut actions = self.protocols[i].handle(event);
+        actions.sort_by_key(action_order);
+        // a replica takes its trace to share it, every peer gets the same one
+        let shared = actions.iter().find_map(|action| match action {
+            Action::Send(_, Event::Trace(from, writes, reset, _)) => Some((*from, writes, reset)),
+            _ => None,
+        });
+        if let Some((from, writes, reset)) = shared {
+            // the trace starts over at a snapshot installed since the last one
+            let installed = reset.as_ref().is_some_and(|(at, _)| *at == from);
+            self.record_trace(i, writes.clone(), installed);
+        }
+        for action in actions {
+            match action {
+                Action::Send(peer, message) => {
+                    if let Event::Commit(req, seq, deps, ins, _, _, _) = &message {
+                        self.check_commit(*ins, req, *seq, deps)?;
+                    }
+                    let j = match self.addrs.iter().position(|a| *a == peer) {
+                        Some(j) => j,
+                        None => continue,
+                    };
+                    // no random draw without duplicates, so that earlier seeds replay the same
+                    if self.config.duplicate > 0.0 && self.rng.gen::<f64>() < self.config.duplicate
+                    {
+                        if let Some(at) = self.transmit(self.addrs[i], peer) {
+                            self.schedule(at, SimEvent::Step(j, message.clone()));
+                        }
+                    }
+                    if let Some(at) = self.transmit(self.addrs[i], peer) {
+                        self.schedule(at, SimEvent::Step(j, message));
+                    }
+                }
+                Action::Reply(reply, addr) => {
+                    let c = match self.clients.iter().position(|c| c.addr == addr) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    if let Some(at) = self.transmit(self.addrs[i], addr) {
+                        self.schedule(at, SimEvent::Reply(c, reply));
+                    }
+                }
+                Action::Halt(reason) => return Err(reason),
+                Action::Disconnect(_) | Action::Save(_, _) => (),
+            }
+        }
+        Ok(())
+    }
+
+    // sends the next request of client c, to the replica it is attached to
+    fn request(&mut self, c: usize) {
+        let key: String = if self.rng.gen::<f64>() < self.config.conflict {
+            "hello".to_string()
+        } else {
+            (&mut self.rng)
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect()
+        };
+        let number = self.clients[c].next_number;
+        self.clients[c].next_number += 1;
+        let coin = self.rng.gen::<f64>();
+        let cmd = if coin < self.config.increments {
+            KvCommand::Increment(key, 1)
+        } else if coin < self.config.increments + self.config.reads {
+            KvCommand::Read(key)
+        } else {
+            KvCommand::Write(key, format!("{}.{}", c + 1, number))
+        };
+        let time = self.now.as_micros() as u64;
+        self.history.push(HistoryEvent::Invoke(
+            c as u64 + 1,
+            number,
+            cmd.clone(),
+            time,
+        ));
+        // every earlier request of the client was answered
+        let addr = self.clients[c].addr;
+        let req = ClientRequest::Command(cmd, addr, (c as u64 + 1, number, number));
+        self.send_request(c, req, c % self.addrs.len());
+    }
+
+    fn send_request(&mut self, c: usize, req: ClientRequest<KvCommand>, replica: usize) {
+        let (addr, number) = match req.client() {
+            Some((addr, (_, number, _))) => (addr, number),
+            None => return,
+        };
+        self.clients[c].pending = Some((req.clone(), replica));
+        if let Some(at) = self.transmit(addr, self.addrs[replica]) {
+            self.schedule(at, SimEvent::Step(replica, Event::ReceivedRequest(req)));
+        }
+        let timeout = self.now + self.config.request_timeout;
+        self.schedule(timeout, SimEvent::Timeout(c, number));
+    }
+
+    // a client gets the same reply every time a request is answered
+    fn reply(&mut self, c: usize, reply: ClientReply) -> Result<(), String> {
+        let ClientReply::Reply(result, number) = reply;
+        let client = &mut self.clients[c];
+        if let Some(previous) = client.replies.get(&number) {
+            if *previous != result {
+                return Err(format!(
+                    "client {} got {:?} and {:?} as replies to request {}",
+                    c + 1,
+                    previous,
+                    result,
+                    number
+                ));
+            }
+            return Ok(());
+        }
+        client.replies.insert(number, result.clone());
+        let time = self.now.as_micros() as u64;
+        self.history
+            .push(HistoryEvent::Complete(c as u64 + 1, number, result, time));
+
+        let answered = matches!(&client.pending, Some((req, _))
+            if req.client().map(|(_, id)| id.1) == Some(number));
+        if answered {
+            client.pending = None;
+            if self.now < self.config.duration {
+                self.schedule(self.now + self.config.think_time, SimEvent::Request(c));
+            }
+        }
+        Ok(())
+    }
+
+    // sends a request that was not answered in time to the next replica
+    fn timeout(&mut self, c: usize, number: u64) {
+        if let Some((req, replica)) = self.clients[c].pending.clone() {
+            if req.client().map(|(_, id)| id.1) == Some(number) {
+                let next = (replica + 1) % self.addrs.len();
+                self.send_request(c, req, next);
+            }
+        }
+    }
+
+    fn record_trace(&mut self, i: usize, writes: Vec<(Instance, String)>, reset: bool) {
+        if reset {
+            self.traces[i].clear();
+        }
+        for (ins, key) in writes {
+            self.traces[i].entry(key).or_default().push(ins);
+        }
+    }
+
+    // replicas write every key in the same order: the writes two replicas both executed come
+    // in the same order in their traces, a replica that installed a snapshot lacks the writes
+    // before it
+    fn check_order(&self) -> Result<(), String> {
+        for (i, trace) in self.traces.iter().enumerate() {
+            for (j, other) in self.traces.iter().enumerate().skip(i + 1) {
+                for (key, writes) in trace {
+                    let other_writes = match other.get(key) {
+                        Some(other_writes) => other_writes,
+                        None => continue,
+                    };
+                    let ours: Vec<&Instance> =
+                        writes.iter().filter(|w| other_writes.contains(w)).collect();
+                    let theirs: Vec<&Instance> =
+                        other_writes.iter().filter(|w| writes.contains(w)).collect();
+                    if let Some(k) = (0..ours.len()).find(|k| ours[*k] != theirs[*k]) {
+                        return Err(format!(
+                            "replicas {} and {} wrote key {} in different orders: {:?} then {:?}, {:?} then {:?}",
+                            i + 1,
+                            j + 1,
+                            key,
+                            &ours[..k],
+                            ours[k],
+                            &theirs[..k],
+                            theirs[k]
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // replicas that executed the same instances have the same state, returns how many replicas
+    // executed everything the most advanced replica did
+    fn check_states(&self) -> Result<usize, String> {
+        let settled: Vec<(usize, SettledState)> = self
+            .states
+            .iter()
+            .enumerate()
+            .filter_map(|(i, state)| Some((i, Replica::atomic_settled_state(state.clone())?)))
+            .collect();
+        for (i, (prefix, state)) in settled.iter() {
+            for (j, (other_prefix, other_state)) in settled.iter() {
+                if i < j && prefix == other_prefix && state != other_state {
+                    return Err(format!(
+                        "replicas {} and {} executed the same instances but have states {} and {}",
+                        i + 1,
+                        j + 1,
+                        state,
+                        other_state
+                    ));
+                }
+            }
+        }
+
+        let total = |prefix: &HashMap<u8, u64>| prefix.values().sum::<u64>();
+        let most = settled
+            .iter()
+            .map(|(_, (p, _))| total(p))
+            .max()
+            .unwrap_or(0);
+        Ok(settled
+            .iter()
+            .filter(|(_, (p, _))| total(p) == most)
+            .count())
+    }
+
+    // runs the simulation, returns a report of the run or the first invariant it broke
+    // local timers every replica runs
+    fn timers(&self) -> Vec<Event<KvCommand>> {
+        let mut timers: Vec<Event<KvCommand>> = vec![
+            Event::Heartbeat,
+            Event::CheckTimeouts,
+            Event::TakeCheckpoint,
+        ];
+        if self.replica_config.thrifty {
+            timers.push(Event::CheckThrifty);
+        }
+        if self.replica_config.check_order {
+            timers.push(Event::ShareTrace);
+        }
+        timers
+    }
+
+    // rejects latency and timer ranges the run cannot draw from
+    pub fn check(&self) -> Result<(), String> {
+        let (min, max) = self.config.latency;
+        if min > max {
+            return Err(format!(
+                "the minimum latency {:?} is above the maximum {:?}",
+                min, max
+            ));
+        }
+        for timer in self.timers() {
+            if self.period(&timer) == Some(Duration::ZERO) {
+                return Err(format!("the {} period is zero", timer.name()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<String, String> {
+        let timers = self.timers();
+        // replicas do not start in lockstep
+        for i in 0..self.addrs.len() {
+            for timer in timers.iter() {
+                let period = self.period(timer).unwrap();
+                let at = self.rng.gen_range(Duration::ZERO..period);
+                self.schedule(at, SimEvent::Step(i, timer.clone()));
+            }
+        }
+        for c in 0..self.clients.len() {
+            let at = self.rng.gen_range(Duration::ZERO..=self.config.think_time);
+            self.schedule(at, SimEvent::Request(c));
+        }
+
+        let end = self.config.duration * 2;
+        while let Some(((at, _), event)) = self.queue.pop_first() {
+            if at > end {
+                break;
+            }
+            self.now = at;
+            self.clock.advance_to(at);
+            let res = match event {
+                SimEvent::Step(i, event) => self.step(i, event),
+                SimEvent::Request(c) => {
+                    self.request(c);
+                    Ok(())
+                }
+                SimEvent::Reply(c, reply) => self.reply(c, reply),
+                SimEvent::Timeout(c, number) => {
+                    self.timeout(c, number);
+                    Ok(())
+                }
+            };
+            res.map_err(|violation| format!("at {:?}: {}", at, violation))?;
+        }
+
+        for i in 0..self.states.len() {
+            let (writes, reset) = Replica::atomic_take_trace(self.states[i].clone());
+            self.record_trace(i, writes, reset.is_some());
+        }
+        self.check_order()?;
+        let settled = self.check_states()?;
+        check_linearizable(&self.history)?;
+
+        let requests: u64 = self.clients.iter().map(|c| c.next_number).sum();
+        let replies: usize = self.clients.iter().map(|c| c.replies.len()).sum();
+        Ok(format!(
+            "Seed {}: {} of {} requests answered, {} instances committed, {} of {} messages dropped, {} of {} replicas up to date, history linearizable",
+            self.config.seed,
+            replies,
+            requests,
+            self.committed.len(),
+            self.dropped,
+            self.sent,
+            settled,
+            self.addrs.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a run of --simulate with the default workload and no faults
+    fn sim_config(seed: u64, n: u8) -> SimConfig {
+        SimConfig {
+            seed,
+            n,
+            clients: 5,
+            duration: Duration::from_millis(5000),
+            latency: (Duration::from_millis(1), Duration::from_millis(10)),
+            drop: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            conflict: 0.5,
+            increments: 0.0,
+            reads: 0.0,
+            think_time: Duration::from_millis(20),
+            request_timeout: Duration::from_millis(1000),
+            partitions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn duplicated_replies_count_once() {
+        // a duplicated PreAcceptOK used to complete a fast quorum of 5 replicas
+        let config = SimConfig {
+            drop: 0.05,
+            reorder: 0.2,
+            duplicate: 0.3,
+            ..sim_config(1, 5)
+        };
+        Simulation::new(config, ReplicaConfig::default())
+            .run()
+            .unwrap();
+    }
+
+    #[test]
+    fn recovery_does_not_pick_between_disagreeing_preaccepts() {
+        // recovery used to accept whichever of two different PreAccepted replies came first,
+        // and (2, 28) committed with seq 44 and with seq 43
+        let config = SimConfig {
+            duration: Duration::from_millis(8000),
+            drop: 0.1,
+            reorder: 0.3,
+            conflict: 0.7,
+            increments: 0.2,
+            reads: 0.3,
+            ..sim_config(126, 3)
+        };
+        Simulation::new(config, ReplicaConfig::default())
+            .run()
+            .unwrap();
+    }
+
+    #[test]
+    fn an_instance_committed_twice_differently_is_reported() {
+        let mut sim = Simulation::new(sim_config(1, 3), ReplicaConfig::default());
+        let deps = HashSet::from([(2, 1)]);
+        sim.check_commit((1, 1), &ClientRequest::NoOp, 2, &deps)
+            .unwrap();
+        sim.check_commit((1, 1), &ClientRequest::NoOp, 2, &deps)
+            .unwrap();
+        sim.check_commit((1, 1), &ClientRequest::NoOp, 3, &deps)
+            .unwrap_err();
+        sim.check_commit((1, 1), &ClientRequest::NoOp, 2, &HashSet::new())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn writes_in_different_orders_are_reported() {
+        let mut sim = Simulation::new(sim_config(1, 3), ReplicaConfig::default());
+        let trace = |writes: &[Instance]| HashMap::from([("x".to_string(), writes.to_vec())]);
+        // replica 3 installed a snapshot of (1, 1)
+        sim.traces = vec![
+            trace(&[(1, 1), (2, 1), (3, 1)]),
+            trace(&[(1, 1), (2, 1)]),
+            trace(&[(2, 1), (3, 1)]),
+        ];
+        sim.check_order().unwrap();
+
+        sim.traces[1] = trace(&[(2, 1), (1, 1)]);
+        sim.check_order().unwrap_err();
+    }
+
+    #[test]
+    fn empty_latency_and_timer_ranges_are_rejected() {
+        let replica_config = ReplicaConfig::default();
+        Simulation::new(sim_config(1, 3), replica_config.clone())
+            .check()
+            .unwrap();
+
+        let config = SimConfig {
+            latency: (Duration::from_millis(10), Duration::from_millis(5)),
+            ..sim_config(1, 3)
+        };
+        Simulation::new(config, replica_config.clone())
+            .check()
+            .unwrap_err();
+
+        let zero_heartbeat = ReplicaConfig {
+            heartbeat_interval: Duration::ZERO,
+            ..replica_config.clone()
+        };
+        Simulation::new(sim_config(1, 3), zero_heartbeat)
+            .check()
+            .unwrap_err();
+
+        // half of a nanosecond rounds down to no time at all
+        let tiny_recovery = ReplicaConfig {
+            recovery_timeout: Duration::from_nanos(1),
+            ..replica_config
+        };
+        Simulation::new(sim_config(1, 3), tiny_recovery)
+            .check()
+            .unwrap_err();
+    }
+
+    #[test]
+    fn a_partitioned_replica_catches_up() {
+        // replica 1 is cut off for the first two seconds
+        let config = SimConfig {
+            partitions: vec![Partition {
+                from: 0.0,
+                until: 2.0,
+                groups: vec![vec![SocketAddr::from(([10, 0, 0, 1], 7000))]],
+            }],
+            ..sim_config(1, 3)
+        };
+        let report = Simulation::new(config, ReplicaConfig::default())
+            .run()
+            .unwrap();
+        assert!(report.contains("3 of 3 replicas up to date"), "{}", report);
+    }
+}
@@ -0,0 +1,48 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+// how a command accesses its keys: commands sharing a key interfere unless both only read it or
+// both update it in a way that commutes (e.g. increments of a counter)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Commutative,
+    Write,
+}
+
+// interference relation of the commands of a state machine: commands that do not interfere
+// may be executed in different orders by different replicas. The relation comes only from the
+// keys and the access of the commands, which the replica indexes to compute dependencies: two
+// commands interfere if they share a key, unless both read it or both update it commutatively
+pub trait Interference {
+    // keys the command reads or writes
+    fn keys(&self) -> Vec<String>;
+
+    fn access(&self) -> Access;
+}
+
+// service replicated by EPaxos: every replica applies the committed commands in an order that
+// respects their interference, so apply must be deterministic and commands that do not
+// interfere must commute
+pub trait StateMachine: Default + fmt::Display + fmt::Debug + Send + 'static {
+    // shown in the command log of the replica through Display
+    type Command: Interference
+        + Serialize
+        + DeserializeOwned
+        + Clone
+        + fmt::Debug
+        + fmt::Display
+        + PartialEq
+        + Send
+        + Sync
+        + 'static;
+
+    // applies cmd and returns the reply to the client
+    fn apply(&mut self, cmd: &Self::Command) -> Option<String>;
+
+    // executed state, sent to replicas that fell behind and written to the write-ahead log
+    fn snapshot(&self) -> String;
+
+    fn restore(snapshot: &str) -> Self;
+}
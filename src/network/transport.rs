@@ -0,0 +1,491 @@
+use async_channel::{unbounded, Receiver, Sender};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use smol::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use smol::Async;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// how the replicas and their clients reach each other
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    // Unix domain sockets, for replicas and clients on the same machine
+    Unix,
+    // channels between the replicas and clients of a single process
+    Memory,
+}
+
+// encoding of the messages of a frame
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    // compact binary encoding (bincode)
+    Binary,
+    // JSON, readable when debugging
+    Json,
+}
+
+// version of the framing and of the encoding of the messages, in the header of every frame
+pub const WIRE_VERSION: u8 = 1;
+
+// longest message a frame may carry, e.g. a snapshot; a longer one means the stream is not made
+// of frames
+const MAX_FRAME: usize = 1 << 30;
+
+// carries messages, e.g. Events between replicas or ClientReplies to clients, over connections
+// from one address to another; a message sent on a connection is received in order, once, unless
+// the connection breaks
+pub trait Transport: Clone + Send + Sync + 'static {
+    // sending end of a connection
+    type Connection: Send + 'static;
+    // accepts the connections to an address
+    type Listener: Send + Sync + 'static;
+    // receiving end of a connection
+    type Incoming: Send + 'static;
+
+    fn bind(&self, addr: SocketAddr) -> impl Future<Output = io::Result<Self::Listener>> + Send;
+
+    fn accept(
+        &self,
+        listener: &Self::Listener,
+    ) -> impl Future<Output = io::Result<Self::Incoming>> + Send;
+
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::Connection>> + Send;
+
+    fn send<M: Serialize + Clone + Send + Sync + 'static>(
+        &self,
+        connection: &mut Self::Connection,
+        message: &M,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    // next message of the connection, None once the other end closed it
+    fn receive<M: DeserializeOwned + Send + 'static>(
+        &self,
+        incoming: &mut Self::Incoming,
+    ) -> impl Future<Output = Option<io::Result<M>>> + Send;
+}
+
+// a byte stream carries one message per frame: the length of the message (4 bytes, big endian),
+// the version, the format of the message (0 for binary, 1 for JSON) and the message itself, so
+// that a receiver decodes both formats
+async fn write_frame<W: AsyncWrite + Unpin, M: Serialize>(
+    writer: &mut W,
+    format: WireFormat,
+    message: &M,
+) -> io::Result<()> {
+    let encoded = match format {
+        WireFormat::Binary => bincode::serialize(message).map_err(|e| e.to_string()),
+        WireFormat::Json => serde_json::to_vec(message).map_err(|e| e.to_string()),
+    };
+    let payload = encoded.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // the receiver rejects a longer frame, and the length must fit in its 4 bytes
+    let len = match u32::try_from(payload.len()) {
+        Ok(len) if payload.len() <= MAX_FRAME => len,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message of {} bytes is longer than a frame", payload.len()),
+            ))
+        }
+    };
+    let code = match format {
+        WireFormat::Binary => 0,
+        WireFormat::Json => 1,
+    };
+    let mut frame = Vec::with_capacity(6 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.push(WIRE_VERSION);
+    frame.push(code);
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame).await
+}
+
+// next message of the stream, None at its end; a message that cannot be decoded is an
+// InvalidData error after which the next frame can still be read, any other error leaves the
+// stream in the middle of a frame
+async fn read_frame<R: AsyncBufRead + Unpin, M: DeserializeOwned>(
+    reader: &mut R,
+) -> Option<io::Result<M>> {
+    match reader.fill_buf().await {
+        Ok([]) => return None,
+        Ok(_) => (),
+        Err(e) => return Some(Err(e)),
+    }
+    let mut header = [0; 6];
+    if let Err(e) = reader.read_exact(&mut header).await {
+        return Some(Err(e));
+    }
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let unsupported = |what: String| Some(Err(io::Error::new(io::ErrorKind::Unsupported, what)));
+    if header[4] != WIRE_VERSION {
+        return unsupported(format!("wire version {} is not supported", header[4]));
+    }
+    if header[5] > 1 || len > MAX_FRAME {
+        return unsupported(format!("frame of format {} and length {}", header[5], len));
+    }
+    // the buffer grows as the payload arrives, a forged length does not allocate it up front
+    let mut payload = Vec::new();
+    if let Err(e) = reader.take(len as u64).read_to_end(&mut payload).await {
+        return Some(Err(e));
+    }
+    if payload.len() < len {
+        let e = io::Error::new(io::ErrorKind::UnexpectedEof, "frame ended early");
+        return Some(Err(e));
+    }
+    let message = match header[5] {
+        0 => bincode::deserialize(&payload).map_err(|e| e.to_string()),
+        _ => serde_json::from_slice(&payload).map_err(|e| e.to_string()),
+    };
+    Some(message.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TcpTransport {
+    format: WireFormat,
+}
+
+impl TcpTransport {
+    pub fn new(format: WireFormat) -> Self {
+        TcpTransport { format }
+    }
+}
+
+impl Transport for TcpTransport {
+    type Connection = Async<TcpStream>;
+    type Listener = Async<TcpListener>;
+    type Incoming = BufReader<Async<TcpStream>>;
+
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Self::Listener> {
+        Async::<TcpListener>::bind(addr)
+    }
+
+    async fn accept(&self, listener: &Self::Listener) -> io::Result<Self::Incoming> {
+        let (stream, _) = listener.accept().await?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Self::Connection> {
+        Async::<TcpStream>::connect(addr).await
+    }
+
+    async fn send<M: Serialize + Clone + Send + Sync + 'static>(
+        &self,
+        connection: &mut Self::Connection,
+        message: &M,
+    ) -> io::Result<()> {
+        write_frame(connection, self.format, message).await
+    }
+
+    async fn receive<M: DeserializeOwned + Send + 'static>(
+        &self,
+        incoming: &mut Self::Incoming,
+    ) -> Option<io::Result<M>> {
+        read_frame(incoming).await
+    }
+}
+
+// the socket of an address is the file <address>.sock in dir, e.g. /tmp/127.0.0.1:6000.sock
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    dir: PathBuf,
+    format: WireFormat,
+}
+
+impl UnixTransport {
+    pub fn new(dir: &str, format: WireFormat) -> Self {
+        UnixTransport {
+            dir: PathBuf::from(dir),
+            format,
+        }
+    }
+
+    fn path(&self, addr: SocketAddr) -> PathBuf {
+        self.dir.join(format!("{}.sock", addr))
+    }
+}
+
+impl Transport for UnixTransport {
+    type Connection = Async<UnixStream>;
+    type Listener = Async<UnixListener>;
+    type Incoming = BufReader<Async<UnixStream>>;
+
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Self::Listener> {
+        // the socket of an earlier run is left behind when it stops
+        let path = self.path(addr);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Async::<UnixListener>::bind(path)
+    }
+
+    async fn accept(&self, listener: &Self::Listener) -> io::Result<Self::Incoming> {
+        let (stream, _) = listener.accept().await?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Self::Connection> {
+        Async::<UnixStream>::connect(self.path(addr)).await
+    }
+
+    async fn send<M: Serialize + Clone + Send + Sync + 'static>(
+        &self,
+        connection: &mut Self::Connection,
+        message: &M,
+    ) -> io::Result<()> {
+        write_frame(connection, self.format, message).await
+    }
+
+    async fn receive<M: DeserializeOwned + Send + 'static>(
+        &self,
+        incoming: &mut Self::Incoming,
+    ) -> Option<io::Result<M>> {
+        read_frame(incoming).await
+    }
+}
+
+// message of any type, as it travels on a channel
+type Boxed = Box<dyn Any + Send>;
+
+// connections are channels that carry the messages themselves, without serializing them; the
+// clones of a transport share the listening addresses, a connection is refused at an address
+// nobody listens on; the simulator does not use it, it delivers messages itself on its virtual
+// clock to replay a run exactly
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Sender<Receiver<Boxed>>>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Connection = Sender<Boxed>;
+    type Listener = Receiver<Receiver<Boxed>>;
+    type Incoming = Receiver<Boxed>;
+
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Self::Listener> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(&addr).is_some_and(|l| !l.is_closed()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (sender, receiver) = unbounded();
+        listeners.insert(addr, sender);
+        Ok(receiver)
+    }
+
+    async fn accept(&self, listener: &Self::Listener) -> io::Result<Self::Incoming> {
+        listener
+            .recv()
+            .await
+            .map_err(|_| io::ErrorKind::NotConnected.into())
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Self::Connection> {
+        let listener = self.listeners.lock().unwrap().get(&addr).cloned();
+        let listener = listener.ok_or(io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        let (sender, receiver) = unbounded();
+        listener
+            .send(receiver)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(sender)
+    }
+
+    async fn send<M: Serialize + Clone + Send + Sync + 'static>(
+        &self,
+        connection: &mut Self::Connection,
+        message: &M,
+    ) -> io::Result<()> {
+        connection
+            .send(Box::new(message.clone()))
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    async fn receive<M: DeserializeOwned + Send + 'static>(
+        &self,
+        incoming: &mut Self::Incoming,
+    ) -> Option<io::Result<M>> {
+        let message = incoming.recv().await.ok()?;
+        Some(message.downcast::<M>().map(|m| *m).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "message of an unexpected type")
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Message = (u64, Option<String>);
+
+    fn frame(format: WireFormat, message: &Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        smol::block_on(write_frame(&mut buf, format, message)).unwrap();
+        buf
+    }
+
+    fn read_all(mut bytes: &[u8]) -> Vec<io::Result<Message>> {
+        smol::block_on(async {
+            let mut messages = Vec::new();
+            while let Some(message) = read_frame(&mut bytes).await {
+                let broken = matches!(&message, Err(e) if e.kind() != io::ErrorKind::InvalidData);
+                messages.push(message);
+                if broken {
+                    break;
+                }
+            }
+            messages
+        })
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_formats() {
+        let messages = vec![(1, Some("hello".to_string())), (2, None)];
+        let mut bytes = frame(WireFormat::Binary, &messages[0]);
+        bytes.extend(frame(WireFormat::Json, &messages[1]));
+        bytes.extend(frame(WireFormat::Json, &messages[0]));
+        bytes.extend(frame(WireFormat::Binary, &messages[1]));
+
+        let read: Vec<Message> = read_all(&bytes).into_iter().map(Result::unwrap).collect();
+        assert_eq!(read, [messages.clone(), messages].concat());
+    }
+
+    #[test]
+    fn an_undecodable_message_is_skipped() {
+        let mut bytes = frame(WireFormat::Json, &(1, None));
+        // valid JSON that is not a message
+        bytes[6..].copy_from_slice(b"\"xxxxxx\"");
+        bytes.extend(frame(WireFormat::Json, &(2, None)));
+
+        let read = read_all(&bytes);
+        assert_eq!(
+            read[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(read[1].as_ref().unwrap(), &(2, None));
+    }
+
+    #[test]
+    fn a_frame_of_another_version_is_rejected() {
+        let mut bytes = frame(WireFormat::Binary, &(1, None));
+        bytes[4] = WIRE_VERSION + 1;
+
+        let read = read_all(&bytes);
+        assert_eq!(read.len(), 1);
+        assert_eq!(
+            read[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn an_oversized_length_is_rejected() {
+        let mut bytes = frame(WireFormat::Binary, &(1, None));
+        bytes[..4].copy_from_slice(&(MAX_FRAME as u32 + 1).to_be_bytes());
+
+        let read = read_all(&bytes);
+        assert_eq!(read.len(), 1);
+        assert_eq!(
+            read[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn a_forged_length_is_not_allocated_up_front() {
+        let mut bytes = frame(WireFormat::Binary, &(1, None));
+        bytes[..4].copy_from_slice(&(MAX_FRAME as u32).to_be_bytes());
+
+        let read = read_all(&bytes);
+        assert_eq!(read.len(), 1);
+        assert_eq!(
+            read[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn a_truncated_frame_is_an_error() {
+        let bytes = frame(WireFormat::Json, &(1, Some("hello".to_string())));
+        for len in [3, 6, bytes.len() - 1] {
+            let read = read_all(&bytes[..len]);
+            assert_eq!(read.len(), 1);
+            assert_eq!(
+                read[0].as_ref().unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    #[test]
+    fn memory_connections_carry_messages_in_order() {
+        smol::block_on(async {
+            let transport = MemoryTransport::new();
+            let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+            let listener = transport.bind(addr).await.unwrap();
+            let mut connection = transport.connect(addr).await.unwrap();
+            let mut incoming = transport.accept(&listener).await.unwrap();
+
+            let messages: Vec<Message> = vec![(1, Some("hello".to_string())), (2, None)];
+            for message in messages.iter() {
+                transport.send(&mut connection, message).await.unwrap();
+            }
+            for message in messages.iter() {
+                let received: Message = transport.receive(&mut incoming).await.unwrap().unwrap();
+                assert_eq!(&received, message);
+            }
+
+            // a message of another type is skipped like one that cannot be decoded
+            transport
+                .send(&mut connection, &"hello".to_string())
+                .await
+                .unwrap();
+            let received = transport.receive::<Message>(&mut incoming).await.unwrap();
+            assert_eq!(received.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            // the receiver sees the end of the connection, the sender a broken one
+            drop(connection);
+            assert!(transport.receive::<Message>(&mut incoming).await.is_none());
+            let mut connection = transport.connect(addr).await.unwrap();
+            drop(transport.accept(&listener).await.unwrap());
+            let sent = transport.send(&mut connection, &messages[0]).await;
+            assert_eq!(sent.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        });
+    }
+
+    #[test]
+    fn memory_addresses_are_bound_once() {
+        smol::block_on(async {
+            let transport = MemoryTransport::new();
+            let addr = SocketAddr::from(([10, 0, 0, 1], 7000));
+            let refused = transport.connect(addr).await.unwrap_err();
+            assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+
+            let listener = transport.bind(addr).await.unwrap();
+            let in_use = transport.bind(addr).await.unwrap_err();
+            assert_eq!(in_use.kind(), io::ErrorKind::AddrInUse);
+
+            // the address is free again once its listener is dropped
+            drop(listener);
+            let refused = transport.connect(addr).await.unwrap_err();
+            assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+            transport.bind(addr).await.unwrap();
+        });
+    }
+}
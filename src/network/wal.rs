@@ -0,0 +1,156 @@
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+// when appended records are forced to disk
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // fsync after every record, before the replica replies
+    Every,
+    // fsync periodically, a crash can lose the records of the last interval
+    Grouped,
+    // never fsync, records survive a crash of the process but not of the machine
+    None,
+}
+
+// append-only log of records, one JSON record per line
+#[derive(Debug)]
+pub struct Wal {
+    path: String,
+    file: File,
+    policy: FsyncPolicy,
+    // records written since the last fsync
+    unsynced: usize,
+}
+
+impl Wal {
+    // opens the log at path, creating it if needed, and returns it with the records it already
+    // holds; a record cut short by a crash ends the log and is cut off, so that the next record
+    // starts a line of its own
+    pub fn open<T: DeserializeOwned>(path: &str, policy: FsyncPolicy) -> io::Result<(Wal, Vec<T>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut records = Vec::new();
+        // length of the log up to the end of its last whole record
+        let mut good = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            match serde_json::from_slice(&line) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+            good += read as u64;
+        }
+        if good < file.metadata()?.len() {
+            file.set_len(good)?;
+        }
+
+        let wal = Wal {
+            path: path.to_string(),
+            file,
+            policy,
+            unsynced: 0,
+        };
+        Ok((wal, records))
+    }
+
+    pub fn append<T: Serialize>(&mut self, record: &T) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.unsynced += 1;
+
+        if self.policy == FsyncPolicy::Every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // replaces the log with records, e.g. fewer records that rebuild the same state; they are
+    // written to a new file renamed over the log, so that a crash leaves either log whole
+    pub fn rewrite<T: Serialize>(&mut self, records: &[T]) -> io::Result<()> {
+        let tmp = format!("{}.tmp", self.path);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for record in records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if self.policy != FsyncPolicy::None {
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // forces the records written since the last call to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a log file of its own for every test
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("wal-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn a_torn_record_is_cut_off() {
+        let path = path("torn");
+        let (mut wal, _) = Wal::open::<u64>(&path, FsyncPolicy::None).unwrap();
+        wal.append(&1u64).unwrap();
+        wal.append(&2u64).unwrap();
+        drop(wal);
+        // a crash in the middle of the third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"12").unwrap();
+        drop(file);
+
+        let (mut wal, records) = Wal::open::<u64>(&path, FsyncPolicy::None).unwrap();
+        assert_eq!(records, vec![1, 2]);
+        wal.append(&3u64).unwrap();
+        let (_, records) = Wal::open::<u64>(&path, FsyncPolicy::None).unwrap();
+        assert_eq!(records, vec![1, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_replaces_the_records() {
+        let path = path("rewrite");
+        let (mut wal, _) = Wal::open::<u64>(&path, FsyncPolicy::Every).unwrap();
+        for i in 1..=10u64 {
+            wal.append(&i).unwrap();
+        }
+        wal.rewrite(&[55u64]).unwrap();
+        wal.append(&11u64).unwrap();
+
+        let (_, records) = Wal::open::<u64>(&path, FsyncPolicy::Every).unwrap();
+        assert_eq!(records, vec![55, 11]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+#!/bin/bash
+
+experiment_len=30
+# every replica starts the schedule from this time, replica 1 is cut off from the others from
+# the 20th to the 30th second
+start=$(date +%s)
+partitions="--partition-schedule ./test_scripts/partition3.json --partition-start $start"
+
+echo "Starting 3 replicas"
+
+# Start replica 1
+./target/release/project --id 1 -n 3 --listener 127.0.0.1:6000 --connections 127.0.0.1:8000 127.0.0.1:9000 $partitions > replica1.log 2> replica1.err &
+# Get its process ID
+PID1=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 2
+./target/release/project --id 2 -n 3 --listener 127.0.0.1:8000 --connections 127.0.0.1:6000 127.0.0.1:9000 $partitions > replica2.log 2> replica2.err &
+# Get its process ID
+PID2=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 3
+./target/release/project --id 3 -n 3 --listener 127.0.0.1:9000 --connections 127.0.0.1:6000 127.0.0.1:8000 $partitions > replica3.log 2> replica3.err &
+# Get its process ID
+PID3=$!
+# Sleep for 10 seconds
+sleep 10
+
+echo "Finished starting 3 replicas"
+
+
+echo "Starting 3 client request generators"
+# Start 3 clients, one for each replica, half of their requests are reads; the client of replica 1
+# knows no other replica, its requests stall while replica 1 is cut off
+./target/release/project --gen 127.0.0.1:6000 --time-sleep 200 --reads 0.5 --experiment-time $experiment_len --listener 127.0.0.1:6001 --history client1.history > client1.log 2>&1 &
+PID4=$!
+
+./target/release/project --gen 127.0.0.1:8000 --time-sleep 200 --reads 0.5 --experiment-time $experiment_len --listener 127.0.0.1:8001 --history client2.history > client2.log 2>&1 &
+PID5=$!
+
+./target/release/project --gen 127.0.0.1:9000 --time-sleep 200 --reads 0.5 --experiment-time $experiment_len --listener 127.0.0.1:9001 --history client3.history > client3.log 2>&1 &
+PID6=$!
+echo "Finished starting 3 client request generators"
+
+echo "Sleeping for $experiment_len seconds"
+
+sleep $experiment_len
+echo "Finished sleeping"
+
+sleep 10
+
+echo "Killing processes"
+# Kill the target/release/project node processes
+kill $PID1 $PID2 $PID3
+
+grep -h "Partition" replica*.log
+
+for i in 1 2 3; do
+    echo "Client $i: $(grep -c 'ms$' client$i.log) requests answered, $(grep -c 'not received' client$i.log) unanswered, longest wait $(grep 'ms$' client$i.log | sort -n | tail -1)"
+done
+
+echo "Checking that the client histories are linearizable"
+./target/release/project --check-history client*.history
+
+# Output that the process is completed
+echo "Test complete, outputs saved"
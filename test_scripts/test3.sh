@@ -30,15 +30,15 @@ echo "Finished starting 3 replicas"
 
 echo "Starting 3 client request generators"
 # Start 3 clients, one for each replica that generate random requests
-./target/release/project --gen 127.0.0.1:6000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:6001 > client1.log 2>&1 &
+./target/release/project --gen 127.0.0.1:6000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:6001 --history client1.history > client1.log 2>&1 &
 PID4=$!
 #sleep 5
 
-./target/release/project --gen 127.0.0.1:8000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:8001 > client2.log 2>&1 &
+./target/release/project --gen 127.0.0.1:8000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:8001 --history client2.history > client2.log 2>&1 &
 PID5=$!
 #sleep 5
 
-./target/release/project --gen 127.0.0.1:9000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:9001 > client3.log 2>&1 &
+./target/release/project --gen 127.0.0.1:9000 --time-sleep 20 --experiment-time $experiment_len --listener 127.0.0.1:9001 --history client3.history > client3.log 2>&1 &
 PID6=$!
 #sleep 5
 echo "Finished starting 3 client request generators"
@@ -69,5 +69,8 @@ echo "Killing processes"
 kill $PID1 $PID2 $PID3 
 
 
+echo "Checking that the client histories are linearizable"
+./target/release/project --check-history client*.history
+
 # Output that the process is completed
 echo "Test complete, outputs saved"
\ No newline at end of file
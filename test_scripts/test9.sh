@@ -0,0 +1,131 @@
+#!/bin/bash
+
+experiment_len=30
+
+echo "Starting 9 replicas"
+
+# Start replica 1
+./target/release/project --id 1 -n 9 --listener 127.0.0.1:8000 --connections 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica1.log 2> replica1.err &
+# Get its process ID
+PID1=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 2
+./target/release/project --id 2 -n 9 --listener 127.0.0.1:9000 --connections 127.0.0.1:8000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica2.log 2> replica2.err &
+# Get its process ID
+PID2=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 3
+./target/release/project --id 3 -n 9 --listener 127.0.0.1:10000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica3.log 2> replica3.err &
+# Get its process ID
+PID3=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 4
+./target/release/project --id 4 -n 9 --listener 127.0.0.1:11000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica4.log 2> replica4.err &
+# Get its process ID
+PID4=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 5
+./target/release/project --id 5 -n 9 --listener 127.0.0.1:12000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica5.log 2> replica5.err &
+# Get its process ID
+PID5=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 6
+./target/release/project --id 6 -n 9 --listener 127.0.0.1:13000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:14000 127.0.0.1:15000 127.0.0.1:16000 > replica6.log 2> replica6.err &
+# Get its process ID
+PID6=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 7
+./target/release/project --id 7 -n 9 --listener 127.0.0.1:14000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:15000 127.0.0.1:16000 > replica7.log 2> replica7.err &
+# Get its process ID
+PID7=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 8
+./target/release/project --id 8 -n 9 --listener 127.0.0.1:15000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:16000 > replica8.log 2> replica8.err &
+# Get its process ID
+PID8=$!
+# Sleep for 1 second
+sleep 1
+
+# Start replica 9
+./target/release/project --id 9 -n 9 --listener 127.0.0.1:16000 --connections 127.0.0.1:8000 127.0.0.1:9000 127.0.0.1:10000 127.0.0.1:11000 127.0.0.1:12000 127.0.0.1:13000 127.0.0.1:14000 127.0.0.1:15000 > replica9.log 2> replica9.err &
+# Get its process ID
+PID9=$!
+# Sleep for 1 second
+sleep 1
+
+# Sleep for 5 seconds
+sleep 5
+
+echo "Finished starting 9 replicas"
+
+
+echo "Starting 9 client request generators"
+# Start 9 clients, one for each replica that generate random requests
+./target/release/project --gen 127.0.0.1:8000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:8001 --history client1.history > client1.log 2>&1 &
+PID10=$!
+./target/release/project --gen 127.0.0.1:9000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:9001 --history client2.history > client2.log 2>&1 &
+PID11=$!
+./target/release/project --gen 127.0.0.1:10000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:10001 --history client3.history > client3.log 2>&1 &
+PID12=$!
+./target/release/project --gen 127.0.0.1:11000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:11001 --history client4.history > client4.log 2>&1 &
+PID13=$!
+./target/release/project --gen 127.0.0.1:12000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:12001 --history client5.history > client5.log 2>&1 &
+PID14=$!
+./target/release/project --gen 127.0.0.1:13000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:13001 --history client6.history > client6.log 2>&1 &
+PID15=$!
+./target/release/project --gen 127.0.0.1:14000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:14001 --history client7.history > client7.log 2>&1 &
+PID16=$!
+./target/release/project --gen 127.0.0.1:15000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:15001 --history client8.history > client8.log 2>&1 &
+PID17=$!
+./target/release/project --gen 127.0.0.1:16000 --time-sleep 50 --experiment-time $experiment_len --listener 127.0.0.1:16001 --history client9.history > client9.log 2>&1 &
+PID18=$!
+echo "Finished starting 9 client request generators"
+
+echo "Sleeping for 30 seconds"
+# Sleep for 30 seconds
+
+sleep $experiment_len
+echo "Finished sleeping"
+
+sleep 10
+
+
+echo "Saving replica states"
+# Fetch the replica states from all 9 nodes and write the output to files
+./target/release/project --save 127.0.0.1:8000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:9000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:10000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:11000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:12000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:13000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:14000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:15000 > /dev/null 2>&1 &
+./target/release/project --save 127.0.0.1:16000 > /dev/null 2>&1 &
+echo "Finished saving replica states"
+
+sleep 10
+
+echo "Killing processes"
+# Kill the target/release/project node processes
+kill $PID1 $PID2 $PID3 $PID4 $PID5 $PID6 $PID7 $PID8 $PID9 
+
+
+echo "Checking that the client histories are linearizable"
+./target/release/project --check-history client*.history
+
+# Output that the process is completed
+echo "Test complete, outputs saved"
\ No newline at end of file